@@ -0,0 +1,493 @@
+use std::cmp::max;
+use std::fmt::{Display, Formatter};
+
+use bio::alignment::AlignmentOperation;
+use bio::alignment::pairwise::{Aligner, Scoring};
+use itertools::{EitherOrBoth, Itertools};
+use strum::VariantArray;
+
+use crate::types::{QualityVoteTotal, QualityVoteVec};
+
+// the order bases are tallied in
+const BASES: [u8; 4] = *b"ATCG";
+
+// what a family's reads say about one position
+#[derive(Clone, Default)]
+pub(crate) struct BaseVotes {
+    // log-likelihood of each base being the true one
+    log_likelihoods: [f64; 4],
+    // how many reads called each base, and their summed Phred qualities
+    counts: [u32; 4],
+    quality_totals: [QualityVoteTotal; 4],
+    // when aligning: how many reads had no base here
+    deletions: u32,
+    // and how many had bases just before here that the seed doesn't, with what they say those bases are
+    insertions: u32,
+    inserted: Vec<BaseVotes>,
+}
+
+// everything a family's reads say, position by position
+#[derive(Default)]
+pub(crate) struct FamilyVotes {
+    pub(crate) size: usize,
+    pub(crate) votes: (QualityVoteVec, QualityVoteVec),
+    // when aligning, the first pair's reads, which every later read is aligned to
+    seeds: (Vec<u8>, Vec<u8>),
+}
+
+// the reads behind one consensus base
+#[derive(Clone, Copy, Default)]
+pub(crate) struct BaseTally {
+    // summed Phred quality of the reads calling each of A, T, C and G
+    pub(crate) quality_totals: [QualityVoteTotal; 4],
+    // reads with a base here, and how many of them called the consensus base (masked or not)
+    pub(crate) depth: u32,
+    pub(crate) agreeing: u32,
+}
+
+// one consensus read: sequence, (not yet offset) qualities, and the tally behind each base
+#[derive(Default)]
+pub(crate) struct ConsensusRead {
+    pub(crate) seq: Vec<u8>,
+    pub(crate) qual: Vec<u8>,
+    pub(crate) tallies: Vec<BaseTally>,
+}
+
+impl ConsensusRead {
+    fn push(&mut self, (base, quality): (u8, u8), tally: BaseTally) {
+        self.seq.push(base);
+        self.qual.push(quality);
+        self.tallies.push(tally);
+    }
+}
+
+pub(crate) struct ConsensusPair {
+    // pairs in the family (or both families, for a duplex)
+    pub(crate) size: usize,
+    pub(crate) reads: (ConsensusRead, ConsensusRead),
+}
+
+impl ConsensusPair {
+    // fraction of the family's bases that disagree with the consensus
+    pub(crate) fn disagreement_rate(&self) -> f64 {
+        let (depth, agreeing) = self.reads.0.tallies.iter().chain(&self.reads.1.tallies)
+            .fold((0u64, 0u64), |(depth, agreeing), tally| {
+                (depth + tally.depth as u64, agreeing + tally.agreeing as u64)
+            });
+        match depth {
+            0 => 0.0,
+            _ => (depth - agreeing) as f64 / depth as f64,
+        }
+    }
+}
+
+// chance of an error in either of two independent steps, where two errors can cancel out to the right base
+fn error_in_either(first: f64, second: f64) -> f64 {
+    first + second - 4.0 / 3.0 * first * second
+}
+
+fn phred_to_error(phred: u8) -> f64 {
+    10f64.powf(-(phred as f64) / 10.0)
+}
+
+// calls a consensus base at each position of a family of reads, as fgbio's CallMolecularConsensusReads does: every
+// read's base counts for as much as its quality says it should, and the consensus quality is the chance the winning
+// base is wrong given all of them
+pub(crate) struct ConsensusCaller {
+    // highest Phred quality a consensus base can be given
+    pub(crate) max_quality: u8,
+    // chance of an error before the UMI was attached (e.g. DNA damage), which no number of reads can correct
+    pub(crate) pre_umi_error: Option<f64>,
+    // chance of an error after the UMI was attached (e.g. PCR) that the base quality doesn't account for
+    pub(crate) post_umi_error: Option<f64>,
+    // families with fewer pairs than this get no consensus
+    pub(crate) min_family_size: usize,
+    // positions where fewer than this fraction of the reads with a base there agree with the consensus are N
+    pub(crate) min_agreement: f64,
+    // as are positions whose consensus quality would be lower than this
+    pub(crate) min_quality: u8,
+    // align reads to the family's first before voting, so an indel doesn't put every later base in the wrong position
+    pub(crate) align: bool,
+}
+
+impl Default for ConsensusCaller {
+    fn default() -> Self {
+        ConsensusCaller {
+            max_quality: 90,
+            pre_umi_error: None,
+            post_umi_error: None,
+            min_family_size: 1,
+            min_agreement: 0.0,
+            min_quality: 0,
+            align: false,
+        }
+    }
+}
+
+impl ConsensusCaller {
+    pub(crate) fn new(max_quality: u8, pre_umi_phred: Option<u8>, post_umi_phred: Option<u8>) -> Self {
+        ConsensusCaller {
+            max_quality,
+            pre_umi_error: pre_umi_phred.map(phred_to_error),
+            post_umi_error: post_umi_phred.map(phred_to_error),
+            ..Default::default()
+        }
+    }
+
+    // add one read's base (and its Phred quality, already offset-corrected) to the log-likelihoods at its position
+    pub(crate) fn observe(&self, votes: &mut BaseVotes, base: u8, quality: u8) {
+        let Some(observed) = BASES.iter().position(|candidate| candidate.eq_ignore_ascii_case(&base)) else {
+            // N; this read abstains for this base
+            return;
+        };
+        let mut error = phred_to_error(quality);
+        if let Some(post_umi_error) = self.post_umi_error {
+            error = error_in_either(error, post_umi_error);
+        }
+        // past 3 in 4, a base says nothing (Q0 would otherwise rule out the base it calls)
+        let error = error.min(0.75);
+
+        votes.counts[observed] += 1;
+        votes.quality_totals[observed] += quality as QualityVoteTotal;
+        for (index, log_likelihood) in votes.log_likelihoods.iter_mut().enumerate() {
+            *log_likelihood += match index == observed {
+                true => (1.0 - error).ln(),
+                false => (error / 3.0).ln(),
+            };
+        }
+    }
+
+    // vote with each base of a read (qualities offset-corrected) in the position it belongs in
+    fn add_read(&self, votes: &mut QualityVoteVec, seed: &mut Vec<u8>, seq: &[u8], qual: &[u8]) {
+        let seq = seq.to_ascii_uppercase();
+        if !self.align || seed.is_empty() {
+            if self.align {
+                seed.clone_from(&seq);
+            }
+            // stretch to size sufficient to fit data
+            votes.resize(max(votes.len(), seq.len()), Default::default());
+            for (vec_to_update, (base, quality)) in votes.iter_mut().zip(seq.iter().zip(qual)) {
+                self.observe(vec_to_update, *base, *quality);
+            }
+            return;
+        }
+
+        // reads all start at the same place, but can end anywhere
+        let scoring = Scoring::from_scores(-5, -1, 1, -1).xclip_suffix(0).yclip_suffix(0);
+        let alignment = Aligner::with_scoring(scoring).custom(&seq, seed);
+        let (mut read_position, mut column) = (0, 0);
+        // how far into a run of inserted bases the read is
+        let mut insertion_offset = None;
+        for operation in alignment.operations {
+            match operation {
+                AlignmentOperation::Match | AlignmentOperation::Subst => {
+                    self.observe(&mut votes[column], seq[read_position], qual[read_position]);
+                    read_position += 1;
+                    column += 1;
+                }
+                AlignmentOperation::Del => {
+                    votes[column].deletions += 1;
+                    column += 1;
+                }
+                // past the seed's end there's nothing to insert into, so those bases vote as if there were no seed
+                AlignmentOperation::Ins if column >= seed.len() => {
+                    votes.resize(max(votes.len(), column + 1), Default::default());
+                    self.observe(&mut votes[column], seq[read_position], qual[read_position]);
+                    read_position += 1;
+                    column += 1;
+                }
+                AlignmentOperation::Ins => {
+                    let position = &mut votes[column];
+                    let offset = match insertion_offset {
+                        Some(offset) => offset + 1,
+                        None => {
+                            position.insertions += 1;
+                            0
+                        }
+                    };
+                    if position.inserted.len() <= offset {
+                        position.inserted.push(Default::default());
+                    }
+                    self.observe(&mut position.inserted[offset], seq[read_position], qual[read_position]);
+                    read_position += 1;
+                    insertion_offset = Some(offset);
+                    continue;
+                }
+                AlignmentOperation::Xclip(length) if alignment.yend == seed.len() => {
+                    // the read is longer than the seed
+                    votes.resize(max(votes.len(), column + length), Default::default());
+                    for (vec_to_update, (base, quality)) in votes[column..].iter_mut()
+                        .zip(seq[read_position..].iter().zip(&qual[read_position..])) {
+                        self.observe(vec_to_update, *base, *quality);
+                    }
+                    read_position += length;
+                    column += length;
+                }
+                AlignmentOperation::Xclip(length) => read_position += length,
+                AlignmentOperation::Yclip(length) => column += length,
+            }
+            insertion_offset = None;
+        }
+    }
+
+    // add a pair's reads (qualities offset-corrected) to its family's votes
+    pub(crate) fn add_pair(&self, family: &mut FamilyVotes, forward: (&[u8], &[u8]), reverse: (&[u8], &[u8])) {
+        family.size += 1;
+        self.add_read(&mut family.votes.0, &mut family.seeds.0, forward.0, forward.1);
+        self.add_read(&mut family.votes.1, &mut family.seeds.1, reverse.0, reverse.1);
+    }
+
+    // the base with the highest posterior (from a flat prior) and the Phred-scaled chance it's wrong. a position no
+    // read had a base for is N
+    pub(crate) fn call(&self, votes: &BaseVotes) -> (u8, u8) {
+        let log_likelihoods = &votes.log_likelihoods;
+        if log_likelihoods.iter().all(|log_likelihood| *log_likelihood == log_likelihoods[0]) {
+            return (b'N', 2.min(self.max_quality));
+        }
+
+        let (winner, best) = log_likelihoods.iter().copied().enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        // posteriors relative to the winner's, so nothing underflows
+        let others = log_likelihoods.iter().enumerate()
+            .filter(|(index, _)| *index != winner)
+            .map(|(_, log_likelihood)| (log_likelihood - best).exp())
+            .sum::<f64>();
+        let mut error = others / (1.0 + others);
+        if let Some(pre_umi_error) = self.pre_umi_error {
+            error = error_in_either(error, pre_umi_error);
+        }
+
+        let quality = (-10.0 * error.log10()).round().min(self.max_quality as f64);
+        (BASES[winner], quality as u8)
+    }
+
+    // one position's consensus, masked if it falls short of the thresholds
+    fn call_position(&self, position: &BaseVotes, family_masks: &mut FamilyMasks) -> ((u8, u8), BaseTally) {
+        let (base, quality) = self.call(position);
+        let called = position.counts.iter().sum::<u32>();
+        let agreeing = BASES.iter().position(|candidate| *candidate == base)
+            .map_or(0, |index| position.counts[index]);
+        let tally = BaseTally { quality_totals: position.quality_totals, depth: called, agreeing };
+        let call = if base == b'N' {
+            (base, quality)
+        } else if (agreeing as f64) < self.min_agreement * called as f64 {
+            family_masks.disagreement += 1;
+            (b'N', 2.min(self.max_quality))
+        } else if quality < self.min_quality {
+            family_masks.low_quality += 1;
+            (b'N', 2.min(self.max_quality))
+        } else {
+            (base, quality)
+        };
+        (call, tally)
+    }
+
+    // one read's consensus. when aligning, a base most reads have an extra copy of goes in, and one most reads lack
+    // comes out
+    fn call_read(&self, votes: &QualityVoteVec, family_masks: &mut FamilyMasks) -> ConsensusRead {
+        let mut consensus = ConsensusRead::default();
+        for position in votes {
+            let called = position.counts.iter().sum::<u32>();
+            if position.insertions * 2 > called + position.deletions {
+                for inserted in &position.inserted {
+                    if inserted.counts.iter().sum::<u32>() * 2 > position.insertions {
+                        let (call, tally) = self.call_position(inserted, family_masks);
+                        consensus.push(call, tally);
+                    }
+                }
+            }
+
+            if position.deletions <= called {
+                let (call, tally) = self.call_position(position, family_masks);
+                consensus.push(call, tally);
+            }
+        }
+        consensus
+    }
+
+    // None if the family is too small to be trusted
+    pub(crate) fn call_family(&self, family: &FamilyVotes, consensus_count: &mut ConsensusCount)
+                              -> Option<ConsensusPair> {
+        if family.size < self.min_family_size {
+            consensus_count.too_small += 1;
+            return None;
+        }
+
+        let mut family_masks = FamilyMasks::default();
+        let reads = (self.call_read(&family.votes.0, &mut family_masks),
+                     self.call_read(&family.votes.1, &mut family_masks));
+        consensus_count.add(family_masks);
+        Some(ConsensusPair { size: family.size, reads })
+    }
+
+    // the two strands' consensuses at each position, masked wherever they disagree or only one strand reaches. the
+    // other strand's forward read covers what this strand's reverse read does, and vice versa
+    pub(crate) fn call_duplex(&self, strand: ConsensusPair, other: ConsensusPair, duplex_count: &mut DuplexCount)
+                              -> ConsensusPair {
+        let (mut disagreement, mut one_strand) = (0, 0);
+        let mut combine = |read: ConsensusRead, other_read: ConsensusRead| {
+            let mut combined = ConsensusRead::default();
+            let bases = read.seq.into_iter().zip(read.qual).zip(read.tallies);
+            let other_bases = other_read.seq.into_iter().zip(other_read.qual).zip(other_read.tallies);
+            for position in bases.zip_longest(other_bases) {
+                let (((base, quality), tally), ((other_base, other_quality), other_tally)) = match position {
+                    EitherOrBoth::Both(ours, theirs) => (ours, theirs),
+                    // nothing to check this strand's base against
+                    EitherOrBoth::Left(((_, _), tally)) | EitherOrBoth::Right(((_, _), tally)) => {
+                        one_strand += 1;
+                        combined.push((b'N', 2.min(self.max_quality)), tally);
+                        continue;
+                    }
+                };
+                let call = if base == b'N' || other_base == b'N' {
+                    (b'N', 2.min(self.max_quality))
+                } else if base != other_base {
+                    disagreement += 1;
+                    (b'N', 2.min(self.max_quality))
+                } else {
+                    (base, quality.saturating_add(other_quality).min(self.max_quality))
+                };
+                let mut quality_totals = tally.quality_totals;
+                for (total, other_total) in quality_totals.iter_mut().zip(other_tally.quality_totals) {
+                    *total += other_total;
+                }
+                combined.push(call, BaseTally {
+                    quality_totals,
+                    depth: tally.depth + other_tally.depth,
+                    agreeing: tally.agreeing + other_tally.agreeing,
+                });
+            }
+            combined
+        };
+        let reads = (combine(strand.reads.0, other.reads.1), combine(strand.reads.1, other.reads.0));
+        let duplex = ConsensusPair { size: strand.size + other.size, reads };
+
+        duplex_count.duplexes += 1;
+        if disagreement > 0 {
+            duplex_count.disagreement = (duplex_count.disagreement.0 + 1, duplex_count.disagreement.1 + disagreement);
+        }
+        if one_strand > 0 {
+            duplex_count.one_strand = (duplex_count.one_strand.0 + 1, duplex_count.one_strand.1 + one_strand);
+        }
+        duplex
+    }
+}
+
+// bases masked in one family's consensus
+#[derive(Default)]
+struct FamilyMasks {
+    disagreement: usize,
+    low_quality: usize,
+}
+
+#[derive(Default)]
+pub(crate) struct ConsensusCount {
+    pub(crate) too_small: usize,
+    // families with any base masked for each reason, and how many bases that was
+    pub(crate) disagreement: (usize, usize),
+    pub(crate) low_quality: (usize, usize),
+}
+
+impl ConsensusCount {
+    fn add(&mut self, family_masks: FamilyMasks) {
+        if family_masks.disagreement > 0 {
+            self.disagreement = (self.disagreement.0 + 1, self.disagreement.1 + family_masks.disagreement);
+        }
+        if family_masks.low_quality > 0 {
+            self.low_quality = (self.low_quality.0 + 1, self.low_quality.1 + family_masks.low_quality);
+        }
+    }
+}
+
+impl Display for ConsensusCount {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "families dropped for having fewer pairs than --min-family-size: {}\n\
+        families with bases masked for agreeing less than --min-agreement: {} ({} bases)\n\
+        families with bases masked for consensus quality below --min-consensus-quality: {} ({} bases)",
+               self.too_small, self.disagreement.0, self.disagreement.1, self.low_quality.0, self.low_quality.1)
+    }
+}
+
+// where the two halves of a duplex UMI are read
+#[derive(Clone, Copy, PartialEq, VariantArray)]
+pub(crate) enum DuplexLayout {
+    // A at the start of the forward read, B at the start of the reverse read
+    Split,
+    // A then B at the start of the forward read
+    Inline,
+}
+
+// the UMI the other strand of the same molecule carries: with a UMI of two halves, A then B, one strand reads AB and
+// the other BA
+pub(crate) fn duplex_partner(umi: &[u8]) -> Vec<u8> {
+    let (a, b) = umi.split_at(umi.len() / 2);
+    [b, a].concat()
+}
+
+#[derive(Default)]
+pub(crate) struct DuplexCount {
+    pub(crate) duplexes: usize,
+    pub(crate) single_strand: usize,
+    // duplexes with any base masked for the strands disagreeing, and how many bases that was
+    pub(crate) disagreement: (usize, usize),
+    // and for only one strand's consensus reaching them
+    pub(crate) one_strand: (usize, usize),
+}
+
+impl Display for DuplexCount {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "duplexes built from both strands of a molecule: {}\n\
+        duplexes with bases masked for the strands disagreeing: {} ({} bases)\n\
+        duplexes with bases masked for only one strand's consensus reaching them: {} ({} bases)\n\
+        single-strand consensuses with no family for the other strand: {}",
+               self.duplexes, self.disagreement.0, self.disagreement.1, self.one_strand.0, self.one_strand.1,
+               self.single_strand)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the forward consensus of a family of forward reads, each aligned to the first
+    fn aligned_consensus(reads: &[&[u8]]) -> Vec<u8> {
+        let caller = ConsensusCaller { align: true, ..Default::default() };
+        let mut family = FamilyVotes::default();
+        for read in reads {
+            caller.add_pair(&mut family, (read, &vec![30; read.len()]), (b"ACGT", &[30; 4]));
+        }
+        caller.call_family(&family, &mut Default::default()).unwrap().reads.0.seq
+    }
+
+    #[test]
+    fn homopolymer_insertion() {
+        // a minority's extra A stays out, a majority's goes in
+        assert_eq!(aligned_consensus(&[b"GATCCAAAAGTCAGGTTAC", b"GATCCAAAAGTCAGGTTAC", b"GATCCAAAAAGTCAGGTTAC"]),
+                   b"GATCCAAAAGTCAGGTTAC");
+        assert_eq!(aligned_consensus(&[b"GATCCAAAAGTCAGGTTAC", b"GATCCAAAAAGTCAGGTTAC", b"GATCCAAAAAGTCAGGTTAC"]),
+                   b"GATCCAAAAAGTCAGGTTAC");
+    }
+
+    #[test]
+    fn deletion() {
+        assert_eq!(aligned_consensus(&[b"GATCCTGAGTCAGG", b"GATCCGAGTCAGG", b"GATCCGAGTCAGG"]),
+                   b"GATCCGAGTCAGG");
+        assert_eq!(aligned_consensus(&[b"GATCCTGAGTCAGG", b"GATCCTGAGTCAGG", b"GATCCGAGTCAGG"]),
+                   b"GATCCTGAGTCAGG");
+    }
+
+    #[test]
+    fn read_longer_than_seed() {
+        assert_eq!(aligned_consensus(&[b"GATCCTGAGTCAGG", b"GATCCTGAGTCAGGTTACA"]), b"GATCCTGAGTCAGGTTACA");
+        // with an indel before the overhang, the overhang still lines up
+        assert_eq!(aligned_consensus(&[b"GATCCTGAGTCAGG", b"GATCCTGAGTCAGG", b"GATCCGAGTCAGGTTACA"]),
+                   b"GATCCTGAGTCAGGTTACA");
+    }
+
+    #[test]
+    fn read_shorter_than_seed() {
+        assert_eq!(aligned_consensus(&[b"GATCCTGAGTCAGG", b"GATCCTGA"]), b"GATCCTGAGTCAGG");
+        assert_eq!(aligned_consensus(&[b"GATCCTGAGTCAGG", b"GATCCTGA", b"GATCCTAGA"]), b"GATCCTGAGTCAGG");
+    }
+}
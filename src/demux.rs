@@ -0,0 +1,244 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use bio::io::fastq;
+use strum::VariantArray;
+
+use crate::header::ReadHeader;
+use crate::types::{FastqPair, UMIVec};
+use crate::umi_distance::UMIDistance;
+use crate::whitelist::{Whitelist, WhitelistMiss};
+
+#[derive(Clone, Copy, PartialEq, VariantArray)]
+pub(crate) enum DemuxSource {
+    // at the start of the reads themselves
+    Inline,
+    // i7 and i5 index reads, as bcl2fastq/BCL Convert put them in the forward read's description
+    Header,
+}
+
+// pairs matching no sample go to outputs named as if for a sample called this
+pub(crate) const UNDETERMINED: &str = "undetermined";
+
+pub(crate) struct Sample {
+    pub(crate) name: String,
+    // (forward, reverse); a sample sheet either gives every sample a barcode on a read or gives none of them one
+    pub(crate) barcodes: (Option<UMIVec>, Option<UMIVec>),
+}
+
+// one sample per line, whitespace or comma separated: name, forward barcode, then (optionally) reverse barcode. "-"
+// stands for no barcode on that read, and lines starting with # are ignored
+pub(crate) fn read_sample_sheet(path_buf: &PathBuf) -> io::Result<Vec<Sample>> {
+    let mut samples = vec![];
+    for line in BufReader::new(File::open(path_buf)?).lines() {
+        let line = line?;
+        let mut fields = line.split(|c: char| c == ',' || c.is_whitespace()).filter(|field| !field.is_empty());
+        let Some(name) = fields.next().filter(|name| !name.starts_with('#')) else {
+            continue;
+        };
+
+        let mut barcode = || fields.next()
+            .filter(|barcode| *barcode != "-")
+            .map(|barcode| barcode.to_ascii_uppercase().into_bytes());
+        samples.push(Sample {
+            name: name.to_owned(),
+            barcodes: (barcode(), barcode()),
+        });
+    }
+    Ok(samples)
+}
+
+// where a sample's copy of an output goes: next to it, with the sample name in front
+pub(crate) fn sample_path(path_buf: &Path, sample: &str) -> PathBuf {
+    let file_name = path_buf.file_name().map_or_else(Default::default, |name| name.to_string_lossy());
+    path_buf.with_file_name(format!("{sample}.{file_name}"))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum DemuxMiss {
+    // a barcode was too short, or not close enough to any sample's
+    Unmatched,
+    // a barcode was as close to two samples' barcodes
+    Ambiguous,
+    // each barcode belongs to some sample, but no sample has both; for header indexes, index hopping
+    Combination,
+}
+
+// assigns pairs to samples on up to two barcodes, each corrected on its own first
+pub(crate) struct Demultiplexer {
+    pub(crate) samples: Vec<Sample>,
+    barcode_lengths: (usize, usize),
+    whitelists: (Option<Whitelist>, Option<Whitelist>),
+    by_barcodes: HashMap<(Option<UMIVec>, Option<UMIVec>), usize>,
+}
+
+impl Demultiplexer {
+    pub(crate) fn new(samples: Vec<Sample>, radius: usize) -> Result<Self, String> {
+        let Some(first) = samples.first() else {
+            return Err("sample sheet lists no samples".to_owned());
+        };
+        if first.barcodes == (None, None) {
+            return Err(format!("sample {} has no barcodes", first.name));
+        }
+
+        let barcode_lengths = (
+            first.barcodes.0.as_ref().map_or(0, Vec::len),
+            first.barcodes.1.as_ref().map_or(0, Vec::len)
+        );
+        let mut names = HashSet::new();
+        let mut by_barcodes = HashMap::new();
+        for (index, sample) in samples.iter().enumerate() {
+            if sample.name == UNDETERMINED || !names.insert(&sample.name) {
+                return Err(format!("sample name {} is reserved or used twice", sample.name));
+            }
+
+            let lengths = (
+                sample.barcodes.0.as_ref().map_or(0, Vec::len),
+                sample.barcodes.1.as_ref().map_or(0, Vec::len)
+            );
+            if lengths != barcode_lengths {
+                return Err(format!("sample {}'s barcodes are not the same lengths as sample {}'s",
+                                   sample.name, first.name));
+            }
+
+            let barcodes = [&sample.barcodes.0, &sample.barcodes.1];
+            if barcodes.iter().copied().flatten().flatten().any(|base| !b"ACGT".contains(base)) {
+                return Err(format!("sample {} has a barcode that isn't all A, C, G and T", sample.name));
+            }
+
+            if let Some(other) = by_barcodes.insert(sample.barcodes.clone(), index) {
+                return Err(format!("samples {} and {} have the same barcodes", samples[other].name, sample.name));
+            }
+        }
+
+        let whitelist = |barcodes: Vec<UMIVec>| match barcodes.is_empty() {
+            true => None,
+            false => Some(Whitelist::new(&barcodes, radius, UMIDistance::Hamming)),
+        };
+        let whitelists = (
+            whitelist(samples.iter().filter_map(|sample| sample.barcodes.0.clone()).collect()),
+            whitelist(samples.iter().filter_map(|sample| sample.barcodes.1.clone()).collect()),
+        );
+
+        Ok(Demultiplexer { samples, barcode_lengths, whitelists, by_barcodes })
+    }
+
+    // index into `samples` of the sample these barcodes (forward, reverse) belong to
+    pub(crate) fn assign(&self, observed: (&[u8], &[u8])) -> Result<usize, DemuxMiss> {
+        let correct = |whitelist: &Option<Whitelist>, observed: &[u8]| match whitelist {
+            None => Ok(None),
+            Some(whitelist) => match whitelist.correct(&observed.to_ascii_uppercase()) {
+                Ok(corrected) => Ok(Some(corrected)),
+                Err(WhitelistMiss::Unmatched) => Err(DemuxMiss::Unmatched),
+                Err(WhitelistMiss::Ambiguous) => Err(DemuxMiss::Ambiguous),
+            },
+        };
+
+        let barcodes = (correct(&self.whitelists.0, observed.0)?, correct(&self.whitelists.1, observed.1)?);
+        self.by_barcodes.get(&barcodes).copied().ok_or(DemuxMiss::Combination)
+    }
+
+    // for barcodes at the very start of each read
+    pub(crate) fn assign_inline(&self, read_pair: &FastqPair) -> Result<usize, DemuxMiss> {
+        if read_pair.0.seq().len() < self.barcode_lengths.0 || read_pair.1.seq().len() < self.barcode_lengths.1 {
+            return Err(DemuxMiss::Unmatched);
+        }
+
+        self.assign((&read_pair.0.seq()[..self.barcode_lengths.0], &read_pair.1.seq()[..self.barcode_lengths.1]))
+    }
+
+    // indexes in the forward read's header: `1:N:0:ACGTACGT+TTGGCCAA` is i7 ACGTACGT, i5 TTGGCCAA. indexes read
+    // longer than the sample sheet's are cut down to size
+    pub(crate) fn assign_header(&self, read_pair: &FastqPair) -> Result<usize, DemuxMiss> {
+        let indexes = ReadHeader::parse(&read_pair.0).index.unwrap_or_default();
+        let (i7, i5) = indexes.split_once('+').unwrap_or((indexes, ""));
+
+        match (i7.as_bytes().get(..self.barcode_lengths.0), i5.as_bytes().get(..self.barcode_lengths.1)) {
+            (Some(i7), Some(i5)) => self.assign((i7, i5)),
+            _ => Err(DemuxMiss::Unmatched),
+        }
+    }
+
+    // the pair as if the inline barcodes had never been there
+    pub(crate) fn trim_inline(&self, read_pair: &FastqPair) -> FastqPair {
+        let trim = |record: &fastq::Record, length: usize| fastq::Record::with_attrs(
+            record.id(), record.desc(), &record.seq()[length..], &record.qual()[length..]);
+        (trim(&read_pair.0, self.barcode_lengths.0), trim(&read_pair.1, self.barcode_lengths.1))
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct DemuxMissCount {
+    pub(crate) unmatched: usize,
+    pub(crate) ambiguous: usize,
+    pub(crate) combination: usize,
+}
+
+impl DemuxMissCount {
+    pub(crate) fn add(&mut self, miss: DemuxMiss) {
+        match miss {
+            DemuxMiss::Unmatched => self.unmatched += 1,
+            DemuxMiss::Ambiguous => self.ambiguous += 1,
+            DemuxMiss::Combination => self.combination += 1,
+        }
+    }
+
+    pub(crate) fn undetermined(&self) -> usize {
+        self.unmatched + self.ambiguous
+    }
+}
+
+// combinations are left out, as they're worth reporting on their own
+impl Display for DemuxMissCount {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "barcode not within --demux-mismatches of any sample's: {}\n\
+        barcode equally close to more than one sample's: {}",
+               self.unmatched, self.ambiguous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn demultiplexer() -> Demultiplexer {
+        let sample = |name: &str, i7: &[u8], i5: &[u8]| Sample {
+            name: name.to_owned(),
+            barcodes: (Some(i7.to_vec()), Some(i5.to_vec())),
+        };
+        Demultiplexer::new(vec![
+            sample("a", b"ACGTACGT", b"TTGGCCAA"),
+            sample("b", b"GGTTAACC", b"CATGCATG"),
+        ], 1).unwrap()
+    }
+
+    fn pair(desc: Option<&str>) -> FastqPair {
+        let record = fastq::Record::with_attrs("M00123:45:000000000-ABCDE:1:1101:15589:1331", desc, b"ACGT", b"IIII");
+        (record.clone(), record)
+    }
+
+    #[test]
+    fn header_indexes_within_radius_and_cut_to_size() {
+        let demultiplexer = demultiplexer();
+        assert!(demultiplexer.assign_header(&pair(Some("1:N:0:ACGTACGT+TTGGCCAA"))) == Ok(0));
+        assert!(demultiplexer.assign_header(&pair(Some("1:N:0:GGTTAACA+CATGCATGAT"))) == Ok(1));
+        assert!(demultiplexer.assign_header(&pair(Some("1:N:0:ACGTACGT"))) == Err(DemuxMiss::Unmatched));
+        assert!(demultiplexer.assign_header(&pair(None)) == Err(DemuxMiss::Unmatched));
+    }
+
+    #[test]
+    fn hopped_indexes_are_counted_as_combinations_not_undetermined() {
+        let demultiplexer = demultiplexer();
+        let mut misses = DemuxMissCount::default();
+        for desc in ["1:N:0:ACGTACGT+CATGCATG", "1:N:0:GGTTAACC+TTGGCCAT", "1:N:0:ACGTACGT+GGGGGGGG"] {
+            if let Err(miss) = demultiplexer.assign_header(&pair(Some(desc))) {
+                misses.add(miss);
+            }
+        }
+        assert_eq!((misses.combination, misses.unmatched, misses.undetermined()), (2, 1, 1));
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::PathBuf;
+
+use pluralizer::pluralize;
+
+// everything that stops a run early. each class of failure exits with its own code, so whatever runs grebe can tell
+// failures worth retrying (I/O, truncated inputs, full disks) from ones that need the data or arguments fixed:
+//   2  bad arguments (including primers that aren't IUPAC DNA); clap exits with this too
+//   3  a whitelist or sample sheet grebe can't use
+//   4  an input or output couldn't be opened or read (retryable)
+//   5  refused to overwrite an output that isn't empty
+//   6  a malformed record, or forward and reverse records that aren't mates
+//   7  an input ended partway through a record, as a truncated gzip does (retryable once the file is complete)
+//   8  an output couldn't be written, e.g. for want of disk space (retryable)
+pub(crate) enum GrebeError {
+    InvalidArguments(String),
+    InvalidPrimer(&'static str, String),
+    InvalidList(PathBuf, String),
+    Open(PathBuf, io::Error),
+    Read(PathBuf, usize, io::Error),
+    OverwriteRefused(PathBuf),
+    // file, record number (from 1), what's wrong with it
+    MalformedRecord(PathBuf, usize, String),
+    // record number (from 1) in the forward file, what's wrong with the pairing
+    MatesOutOfSync(usize, String),
+    // records in the forward and reverse files, when they differ but every pair there is has mates' names
+    MateCountsDiffer(usize, usize),
+    TruncatedInput(PathBuf, usize),
+    Write(PathBuf, io::Error),
+}
+
+impl GrebeError {
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self {
+            GrebeError::InvalidArguments(..) | GrebeError::InvalidPrimer(..) => 2,
+            GrebeError::InvalidList(..) => 3,
+            GrebeError::Open(..) | GrebeError::Read(..) => 4,
+            GrebeError::OverwriteRefused(..) => 5,
+            GrebeError::MalformedRecord(..) | GrebeError::MatesOutOfSync(..) | GrebeError::MateCountsDiffer(..) => 6,
+            GrebeError::TruncatedInput(..) => 7,
+            GrebeError::Write(..) => 8,
+        }
+    }
+}
+
+impl Display for GrebeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GrebeError::InvalidArguments(reason) => write!(f, "{reason}; refusing"),
+            GrebeError::InvalidPrimer(which, primer) => {
+                write!(f, "{which} primer {primer} not valid IUPAC DNA alphabet; refusing")
+            }
+            GrebeError::InvalidList(path_buf, reason) => write!(f, "{}: {reason}; refusing", path_buf.display()),
+            GrebeError::Open(path_buf, err) => write!(f, "couldn't open {}: {err}", path_buf.display()),
+            GrebeError::Read(path_buf, record_number, err) => {
+                write!(f, "couldn't read record {record_number} of {}: {err}", path_buf.display())
+            }
+            GrebeError::OverwriteRefused(path_buf) => {
+                write!(f, "refusing to overwrite nonempty file {}", path_buf.display())
+            }
+            GrebeError::MalformedRecord(path_buf, record_number, reason) => {
+                write!(f, "record {record_number} of {} was invalid: {reason}", path_buf.display())
+            }
+            GrebeError::MatesOutOfSync(record_number, reason) => {
+                write!(f, "at record {record_number}, {reason}; the files are out of sync (--repair can re-pair \
+                them by name)")
+            }
+            GrebeError::MateCountsDiffer(forward_count, reverse_count) => {
+                write!(f, "the forward file has {} but the reverse file has {}; the files are out of sync (--repair \
+                can re-pair them by name)", pluralize("record", *forward_count as isize, true),
+                       pluralize("record", *reverse_count as isize, true))
+            }
+            GrebeError::TruncatedInput(path_buf, record_number) => {
+                write!(f, "{} ends partway through record {record_number}; is it a truncated gzip?",
+                       path_buf.display())
+            }
+            GrebeError::Write(path_buf, err) => write!(f, "couldn't write to {}: {err}", path_buf.display()),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use bio::io::fastq;
+
+// where on the flow cell a cluster was
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct FlowcellLocation {
+    pub(crate) lane: u32,
+    pub(crate) tile: u32,
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+}
+
+// what Illumina software writes into a read's header, as far as it could be made out. Casava 1.8 and later:
+//   @<instrument>:<run>:<flow cell>:<lane>:<tile>:<x>:<y>[:<UMI>] <read>:<is filtered>:<control number>:<index>
+// and before that:
+//   @<instrument>:<lane>:<tile>:<x>:<y>#<index>/<read>
+#[allow(dead_code)]  // not every stage needs every field
+pub(crate) struct ReadHeader<'a> {
+    // with any /1 or /2 taken off, so both mates of a pair share it
+    pub(crate) name: &'a str,
+    pub(crate) read: Option<u8>,
+    // Y if the cluster failed the instrument's chastity filter
+    pub(crate) is_filtered: Option<bool>,
+    pub(crate) control_number: Option<u32>,
+    // i7 (then + and i5, if dual indexed) or a sample number
+    pub(crate) index: Option<&'a str>,
+    pub(crate) location: Option<FlowcellLocation>,
+}
+
+impl<'a> ReadHeader<'a> {
+    pub(crate) fn parse(record: &'a fastq::Record) -> Self {
+        let id = record.id();
+        let (name, read) = match id.rsplit_once('/') {
+            Some((name, read @ ("1" | "2"))) => (name, read.parse().ok()),
+            _ => (id, None),
+        };
+        let (coordinates, old_index) = match name.split_once('#') {
+            Some((coordinates, index)) => (coordinates, Some(index)),
+            None => (name, None),
+        };
+
+        let numbers = |fields: &[&str]| fields.iter().map(|field| field.parse().ok()).collect::<Option<Vec<u32>>>();
+        let fields = coordinates.split(':').collect::<Vec<_>>();
+        let location = match fields.len() {
+            5 => numbers(&fields[1..5]),
+            7 | 8 => numbers(&fields[3..7]),
+            _ => None,
+        }.map(|numbers| FlowcellLocation { lane: numbers[0], tile: numbers[1], x: numbers[2], y: numbers[3] });
+
+        let mut header = ReadHeader {
+            name,
+            read,
+            is_filtered: None,
+            control_number: None,
+            index: old_index,
+            location,
+        };
+
+        let comment = record.desc().and_then(|desc| desc.split_whitespace().next()).unwrap_or_default();
+        if let [read, is_filtered @ ("Y" | "N"), control_number, index] = comment.split(':').collect::<Vec<_>>()[..] {
+            header.read = read.parse().ok().or(header.read);
+            header.is_filtered = Some(is_filtered == "Y");
+            header.control_number = control_number.parse().ok();
+            header.index = Some(index);
+        }
+        header
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &str, desc: Option<&str>) -> fastq::Record {
+        fastq::Record::with_attrs(id, desc, b"ACGT", b"IIII")
+    }
+
+    #[test]
+    fn casava_1_8() {
+        let record = record("M00123:45:000000000-ABCDE:1:1101:15589:1331", Some("2:Y:0:ACGTACGT+TTGGCCAA"));
+        let header = ReadHeader::parse(&record);
+        assert_eq!(header.name, "M00123:45:000000000-ABCDE:1:1101:15589:1331");
+        assert_eq!(header.read, Some(2));
+        assert_eq!(header.is_filtered, Some(true));
+        assert_eq!(header.control_number, Some(0));
+        assert_eq!(header.index, Some("ACGTACGT+TTGGCCAA"));
+        assert!(header.location == Some(FlowcellLocation { lane: 1, tile: 1101, x: 15589, y: 1331 }));
+    }
+
+    #[test]
+    fn casava_1_8_with_umi_and_unfiltered() {
+        let record = record("NB501:7:HXXXXBGX2:4:11401:2345:6789:ACGTTGCA", Some("1:N:0:2 extra"));
+        let header = ReadHeader::parse(&record);
+        assert_eq!(header.read, Some(1));
+        assert_eq!(header.is_filtered, Some(false));
+        assert_eq!(header.index, Some("2"));
+        assert!(header.location == Some(FlowcellLocation { lane: 4, tile: 11401, x: 2345, y: 6789 }));
+    }
+
+    #[test]
+    fn legacy() {
+        let record = record("HWUSI-EAS100R:6:73:941:1973#ATCACG/1", None);
+        let header = ReadHeader::parse(&record);
+        assert_eq!(header.name, "HWUSI-EAS100R:6:73:941:1973#ATCACG");
+        assert_eq!(header.read, Some(1));
+        assert_eq!(header.is_filtered, None);
+        assert_eq!(header.index, Some("ATCACG"));
+        assert!(header.location == Some(FlowcellLocation { lane: 6, tile: 73, x: 941, y: 1973 }));
+    }
+
+    #[test]
+    fn anything_else_is_just_a_name() {
+        let record = record("read42/3", Some("sample=x"));
+        let header = ReadHeader::parse(&record);
+        assert_eq!(header.name, "read42/3");
+        assert_eq!(header.read, None);
+        assert_eq!(header.is_filtered, None);
+        assert!(header.location.is_none());
+    }
+}
@@ -0,0 +1,201 @@
+use std::cmp::{max, min};
+use std::fmt::{Display, Formatter};
+
+use bio::alphabets::dna;
+use bio::utils::TextSlice;
+
+use crate::util::check_primer_base;
+
+// shortest overlap between forward and reverse complemented reverse we trust to call an insert length
+const MIN_OVERLAP: usize = 10;
+// fraction of overlapping bases allowed to disagree before an overlap is rejected
+const MAX_OVERLAP_MISMATCH_RATE: f64 = 0.1;
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum InsertClass {
+    Expected,
+    PrimerDimer,
+    OffTarget,
+}
+
+impl Display for InsertClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", match self {
+            InsertClass::Expected => "expected",
+            InsertClass::PrimerDimer => "primer-dimer",
+            InsertClass::OffTarget => "off-target",
+        })
+    }
+}
+
+// Estimate the length of the fragment between the start of `forward` and the start of `reverse`, either from the
+// mates overlapping or from both reading through into adapter. `None` if no acceptable overlap exists, i.e. the insert
+// is longer than the two reads together can span.
+pub(crate) fn estimate_insert_length(forward: TextSlice, reverse: TextSlice) -> Option<usize> {
+    let reverse_rc = dna::revcomp(reverse);
+
+    // try the shortest inserts first; short products (dimers, read-through) are what we are looking for here
+    for insert_length in MIN_OVERLAP..=(forward.len() + reverse_rc.len()).saturating_sub(MIN_OVERLAP) {
+        // in insert coordinates, forward covers [0, forward.len()) and reverse_rc ends at insert_length
+        // if reverse_rc starts before 0, its first bases are adapter read-through
+        let reverse_start = insert_length as isize - reverse_rc.len() as isize;
+        let overlap_start = max(reverse_start, 0) as usize;
+        // anything on forward past insert_length is adapter read-through
+        let overlap_end = min(forward.len(), insert_length);
+
+        if overlap_end < overlap_start + MIN_OVERLAP {
+            continue;
+        }
+
+        let reverse_offset = (overlap_start as isize - reverse_start) as usize;
+        let mismatches = forward[overlap_start..overlap_end].iter()
+            .zip(&reverse_rc[reverse_offset..])
+            .filter(|(a, b)| {
+                // an N can't disagree with anything
+                !a.eq_ignore_ascii_case(b) && !a.eq_ignore_ascii_case(&b'N') && !b.eq_ignore_ascii_case(&b'N')
+            })
+            .count();
+
+        if mismatches as f64 <= (overlap_end - overlap_start) as f64 * MAX_OVERLAP_MISMATCH_RATE {
+            return Some(insert_length);
+        }
+    }
+
+    None
+}
+
+// the insert between the start of `forward` and the start of `reverse`, `insert_length` long, pieced together from
+// whichever read covers each base
+fn assemble_insert(forward: TextSlice, reverse: TextSlice, insert_length: usize) -> Vec<u8> {
+    let reverse_rc = dna::revcomp(reverse);
+    let reverse_start = insert_length as isize - reverse_rc.len() as isize;
+    (0..insert_length)
+        .map(|position| match forward.get(position) {
+            Some(base) => *base,
+            None => reverse_rc[(position as isize - reverse_start) as usize],
+        })
+        .collect()
+}
+
+// fraction of an insert made up of primer: the forward primer at its start and the reverse complement of the reverse
+// primer at its end. where the two overlap (primers annealed to each other, as in a dimer), a base has to agree with
+// both
+fn primer_fraction(insert: TextSlice, forward_primer: TextSlice, reverse_primer_rc: TextSlice) -> f64 {
+    if insert.is_empty() {
+        return 1.0;
+    }
+
+    let reverse_start = insert.len() as isize - reverse_primer_rc.len() as isize;
+    let primer_bases = insert.iter().enumerate()
+        .filter(|(position, base)| {
+            let forward = forward_primer.get(*position)
+                .map(|primer_base| check_primer_base((primer_base, base)));
+            let reverse = usize::try_from(*position as isize - reverse_start).ok()
+                .and_then(|offset| reverse_primer_rc.get(offset))
+                .map(|primer_base| check_primer_base((primer_base, base)));
+            match (forward, reverse) {
+                (Some(forward), Some(reverse)) => forward && reverse,
+                (Some(matches), None) | (None, Some(matches)) => matches,
+                (None, None) => false,
+            }
+        })
+        .count();
+    primer_bases as f64 / insert.len() as f64
+}
+
+pub(crate) struct InsertFilter {
+    // the forward primer and the reverse complement of the reverse primer, if both were given
+    pub(crate) primers: Option<(Vec<u8>, Vec<u8>)>,
+    pub(crate) max_primer_fraction: Option<f64>,
+    pub(crate) min_insert_length: Option<usize>,
+    pub(crate) max_insert_length: Option<usize>,
+}
+
+impl InsertFilter {
+    pub(crate) fn is_active(&self) -> bool {
+        (self.primers.is_some() && self.max_primer_fraction.is_some())
+            || self.min_insert_length.is_some() || self.max_insert_length.is_some()
+    }
+
+    // `forward` should already have its UMI removed, so both reads begin at their primer.
+    pub(crate) fn classify(&self, forward: TextSlice, reverse: TextSlice) -> InsertClass {
+        match estimate_insert_length(forward, reverse) {
+            Some(insert_length) => {
+                if let (Some((forward_primer, reverse_primer_rc)), Some(max_primer_fraction)) =
+                    (&self.primers, self.max_primer_fraction) {
+                    // a real amplicon starts and ends with primer too, but has the target in between
+                    let insert = assemble_insert(forward, reverse, insert_length);
+                    if primer_fraction(&insert, forward_primer, reverse_primer_rc) >= max_primer_fraction {
+                        return InsertClass::PrimerDimer;
+                    }
+                }
+
+                if self.min_insert_length.is_some_and(|min_length| insert_length < min_length)
+                    || self.max_insert_length.is_some_and(|max_length| insert_length > max_length) {
+                    return InsertClass::OffTarget;
+                }
+
+                InsertClass::Expected
+            }
+            None => {
+                // no overlap, so the insert is at least this long
+                let shortest_possible = (forward.len() + reverse.len()).saturating_sub(MIN_OVERLAP) + 1;
+                match self.max_insert_length {
+                    Some(max_length) if shortest_possible > max_length => InsertClass::OffTarget,
+                    _ => InsertClass::Expected
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSERT: &[u8] = b"GATTACAGGCTTCAAGTCCTGAACGTATCG";
+    const ADAPTER: &[u8] = b"AGATCGGAAGAGCACA";
+
+    #[test]
+    fn mates_spanning_the_whole_insert() {
+        assert_eq!(estimate_insert_length(INSERT, &dna::revcomp(INSERT)), Some(INSERT.len()));
+    }
+
+    #[test]
+    fn mates_reading_through_into_adapter() {
+        let insert = &INSERT[..20];
+        let forward = [insert, ADAPTER].concat();
+        let reverse = [&dna::revcomp(insert)[..], ADAPTER].concat();
+        assert_eq!(estimate_insert_length(&forward, &reverse), Some(20));
+        assert_eq!(assemble_insert(&forward, &reverse, 20), insert);
+    }
+
+    #[test]
+    fn partially_overlapping_mates_tolerate_a_mismatch_and_ns() {
+        let mut forward = INSERT[..22].to_vec();
+        forward[15] = b'A';
+        forward[18] = b'N';
+        let reverse = dna::revcomp(&INSERT[8..]);
+        assert_eq!(estimate_insert_length(&forward, &reverse), Some(INSERT.len()));
+        assert_eq!(assemble_insert(&forward, &reverse, INSERT.len()), [&forward[..], &INSERT[22..]].concat());
+    }
+
+    #[test]
+    fn mates_that_never_overlap() {
+        assert_eq!(estimate_insert_length(&[b'A'; 30], &[b'A'; 30]), None);
+    }
+
+    #[test]
+    fn primer_fraction_of_dimer_and_amplicon() {
+        let forward_primer = b"ACGTRACG";
+        let reverse_primer_rc = b"TTGCAAYG";
+        // a dimer is nothing but primer
+        assert_eq!(primer_fraction(b"ACGTAACGTTGCAACG", forward_primer, reverse_primer_rc), 1.0);
+        // where the primers overlap a base has to fit both; positions 5 and 6 only fit the forward primer
+        assert_eq!(primer_fraction(b"ACGTAACGCAACG", forward_primer, reverse_primer_rc), 11.0 / 13.0);
+        // an amplicon has the target in between
+        let amplicon = [&b"ACGTGACG"[..], INSERT, b"TTGCAATG"].concat();
+        assert_eq!(primer_fraction(&amplicon, forward_primer, reverse_primer_rc), 16.0 / amplicon.len() as f64);
+        assert_eq!(primer_fraction(b"", forward_primer, reverse_primer_rc), 1.0);
+    }
+}
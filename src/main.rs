@@ -1,10 +1,11 @@
 use std::cmp::{max, min};
 use std::collections::{HashMap, HashSet};
+use std::io::Write;
 use std::path::PathBuf;
 use std::process::exit;
 
-use bio::alignment::distance::simd::hamming;
 use bio::alphabets::dna;
+use bio::io::fastq;
 use clap::{ArgGroup, ValueEnum, ValueHint};
 use clap::builder::PossibleValue;
 use clap::parser::ValueSource;
@@ -14,26 +15,46 @@ use pluralizer::pluralize;
 use strum::VariantArray;
 
 use pair_handling::UMICollisionResolutionMethod;
-use types::FastqPair;
 
-use crate::pair_handling::PairHandler;
-use crate::reader::make_reader_pair;
-use crate::types::{OutputWriters, UMIVec, WhichRead};
-use crate::util::check_primer;
+use crate::consensus::{ConsensusCaller, DuplexLayout};
+use crate::demux::{read_sample_sheet, sample_path, DemuxMiss, DemuxMissCount, DemuxSource, Demultiplexer, UNDETERMINED};
+use crate::error::GrebeError;
+use crate::insert::InsertFilter;
+use crate::majority::MajorityTieBreak;
+use crate::malformed::RecordFaultCount;
+use crate::mates::{MatePairer, Mates};
+use crate::pair_filter::{PairFilter, PairVerdict};
+use crate::pair_handling::{
+    ExtendTieBreak, Extension, PairDropReason, PairHandler, QualityRanking, QualityScore, QualityTieBreak,
+};
+use crate::reader::{count_records, make_reader_pair};
+use crate::types::{FastqPair, OutputWriters, UMIVec, WhichRead};
+use crate::umi_clustering::{UMIClusterer, UMIClusteringMethod};
+use crate::umi_distance::{umi_template_start, UMIDistance};
+use crate::umi_index::UMIIndex;
+use crate::whitelist::{knee_point, read_whitelist, Whitelist, WhitelistMiss};
 
+mod consensus;
+mod demux;
+mod error;
+mod header;
+mod insert;
+mod majority;
+mod malformed;
+mod mates;
+mod pair_filter;
 mod pair_handling;
 mod reader;
 mod writer;
 mod types;
+mod umi_clustering;
+mod umi_distance;
+mod umi_index;
 mod util;
-
-fn find_within_radius(umi_bins: &HashMap<UMIVec, HashSet<FastqPair>>, umi: &UMIVec, radius: usize)
-                      -> Option<UMIVec> {
-    umi_bins.keys().find(|proposed_umi| hamming(proposed_umi, umi) <= radius as u64).cloned()
-}
+mod whitelist;
 
 impl ValueEnum for UMICollisionResolutionMethod {
-    fn value_variants<'a>() -> &'a [Self] { &Self::VARIANTS }
+    fn value_variants<'a>() -> &'a [Self] { Self::VARIANTS }
 
     fn to_possible_value(&self) -> Option<PossibleValue> {
         Some(match self {
@@ -52,31 +73,317 @@ impl ValueEnum for UMICollisionResolutionMethod {
             UMICollisionResolutionMethod::KeepLongestLeft => PossibleValue::new("keep-longest-left")
                 .alias("kl-left")
                 .alias("kll")
-                .help("keep the longest sequence matched, favor the earlier sequence when tied"),
+                .help("keep the longest pair matched (forward and reverse lengths combined), favor the earlier pair \
+                when tied"),
             UMICollisionResolutionMethod::KeepLongestRight => PossibleValue::new("keep-longest-right")
                 .alias("kl-right")
                 .alias("klr")
-                .help("keep the longest sequence matched, favor the later sequence when tied"),
+                .help("keep the longest pair matched (forward and reverse lengths combined), favor the later pair when \
+                tied"),
             UMICollisionResolutionMethod::KeepLongestExtend => PossibleValue::new("keep-longest-extend")
                 .alias("extend")
                 .alias("kl-extend")
                 .alias("kle")
-                .help("keep the longest sequence, overwrite it if a read found later is longer and agrees completely \
-                on base calls"),
+                .help("keep the longest pair, overwrite it if a pair found later is longer and each of its reads \
+                agrees on base calls with the kept one's as far as both go (within --extend-mismatch-budget); see \
+                --extend-tie-break"),
+            UMICollisionResolutionMethod::KeepHighestQuality => PossibleValue::new("highest-quality")
+                .alias("keep-highest-quality")
+                .alias("best-quality")
+                .alias("khq")
+                .help("keep the pair with the best base qualities, as umi_tools and Picard do; see --quality-score \
+                and --quality-tie-break"),
+            UMICollisionResolutionMethod::Majority => PossibleValue::new("majority")
+                .alias("keep-majority")
+                .alias("most-common")
+                .help("keep the forward and reverse sequences seen together most often, as the first pair seen with \
+                them, noting how many pairs had them (XM:i:) and the family size (cD:i:) in read descriptions; see \
+                --majority-tie-break"),
             UMICollisionResolutionMethod::QualityVote => PossibleValue::new("quality-vote")
                 .alias("quality-voting")
                 .alias("vote")
                 .alias("voting")
                 .alias("qv")
-                .help("create one final sequence by combining base calls and qualities from all matched reads"),
+                .help("create one final sequence by combining base calls and qualities from all matched reads into \
+                the most likely base at each position, with its quality the chance that base is wrong"),
         })
     }
 }
 
+impl ValueEnum for QualityScore {
+    fn value_variants<'a>() -> &'a [Self] { Self::VARIANTS }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Self::Mean => PossibleValue::new("mean")
+                .help("mean base quality, so a shorter pair can win on quality alone"),
+            Self::Sum => PossibleValue::new("sum")
+                .alias("total")
+                .help("summed base quality, so longer pairs are favoured as long as their bases are any good"),
+        })
+    }
+}
+
+impl ValueEnum for QualityTieBreak {
+    fn value_variants<'a>() -> &'a [Self] { Self::VARIANTS }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Self::First => PossibleValue::new("first")
+                .help("keep the pair found first"),
+            Self::Last => PossibleValue::new("last")
+                .help("keep the pair found last"),
+            Self::Longest => PossibleValue::new("longest")
+                .help("keep the longer pair, then the pair found first"),
+        })
+    }
+}
+
+impl ValueEnum for ExtendTieBreak {
+    fn value_variants<'a>() -> &'a [Self] { Self::VARIANTS }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Self::First => PossibleValue::new("first")
+                .help("keep the pair found first"),
+            Self::Last => PossibleValue::new("last")
+                .help("keep the pair found last"),
+            Self::Quality => PossibleValue::new("quality")
+                .alias("highest-quality")
+                .help("keep the pair with the higher summed base quality, then the pair found first"),
+            Self::Merge => PossibleValue::new("merge")
+                .help("if the pairs agree, make each read as long as the longer of the two (so the pair may grow), \
+                taking the better quality base wherever both have one; otherwise keep the pair found first"),
+        })
+    }
+}
+
+impl ValueEnum for MajorityTieBreak {
+    fn value_variants<'a>() -> &'a [Self] { Self::VARIANTS }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Self::First => PossibleValue::new("first")
+                .help("keep the sequences seen first"),
+            Self::Longest => PossibleValue::new("longest")
+                .help("keep the longer sequences, then those seen first"),
+            Self::HighestQuality => PossibleValue::new("highest-quality")
+                .alias("quality")
+                .help("keep the sequences with the best mean base quality over every copy, then those seen first"),
+        })
+    }
+}
+
+impl ValueEnum for UMIClusteringMethod {
+    fn value_variants<'a>() -> &'a [Self] { Self::VARIANTS }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Self::None => PossibleValue::new("none")
+                .help("assign UMIs to bins as pairs are read, within --hr of the first bin found"),
+            Self::Unique => PossibleValue::new("unique")
+                .help("every distinct UMI is its own bin, as with --hr 0, but assigned before any pair is binned"),
+            Self::Percentile => PossibleValue::new("percentile")
+                .help("as unique, but discard UMIs seen less than 1% as often as the median UMI"),
+            Self::Cluster => PossibleValue::new("cluster")
+                .help("merge every group of UMIs linked by --hr (default 1) into its most abundant UMI"),
+            Self::Adjacency => PossibleValue::new("adjacency")
+                .help("most abundant UMIs first, merge each UMI's direct neighbours within --hr (default 1) into it"),
+            Self::Directional => PossibleValue::new("directional")
+                .help("umi_tools' directional method: UMIs within --hr (default 1) of a UMI with at least about twice \
+                their count are merged into it, transitively"),
+        })
+    }
+}
+
+impl ValueEnum for UMIDistance {
+    fn value_variants<'a>() -> &'a [Self] { Self::VARIANTS }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Self::Hamming => PossibleValue::new("hamming")
+                .help("count mismatched bases; fast, but an insertion or deletion looks like many errors"),
+            Self::Levenshtein => PossibleValue::new("levenshtein")
+                .alias("edit")
+                .help("count inserted, deleted and mismatched bases, ignoring whatever an indel pushes past the end \
+                of the UMI; reads are rewritten to begin with their bin's UMI so the template starts where expected"),
+        })
+    }
+}
+
+impl ValueEnum for DuplexLayout {
+    fn value_variants<'a>() -> &'a [Self] { Self::VARIANTS }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Self::Split => PossibleValue::new("split")
+                .help("half A at the start of the forward read and half B at the start of the reverse read, each \
+                trimmed off its read, as duplex sequencing adapters put them"),
+            Self::Inline => PossibleValue::new("inline")
+                .help("both halves at the start of the forward read, A then B"),
+        })
+    }
+}
+
+impl ValueEnum for DemuxSource {
+    fn value_variants<'a>() -> &'a [Self] { Self::VARIANTS }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self {
+            Self::Inline => PossibleValue::new("inline")
+                .help("barcodes at the start of the forward and/or reverse reads, trimmed off once matched"),
+            Self::Header => PossibleValue::new("header")
+                .alias("index")
+                .help("i7 and (optionally) i5 indexes at the end of the forward read's description, as in \
+                1:N:0:ACGTACGT+TTGGCCAA"),
+        })
+    }
+}
+
+// a value parser for options that are a fraction of something: between 0 and 1, inclusive
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        Ok(_) => Err("must be between 0 and 1".to_owned()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+// with an indel-tolerant distance, the UMI actually read may not be umi_length long; rewrite the forward read to have
+// the UMI it is binned under (starting `umi_start` bases in, after any cell barcode) so everything downstream finds the
+// template where it expects
+fn realign_to_umi(read_pair: &FastqPair, umi: &UMIVec, umi_start: usize, anchor: &[u8], max_shift: usize)
+                  -> Option<FastqPair> {
+    let template_start = umi_start + umi_template_start(umi, anchor, &read_pair.0.seq()[umi_start..], max_shift);
+    let umi_end = umi_start + umi.len();
+    if template_start == umi_end || template_start > read_pair.0.seq().len() {
+        return None;
+    }
+
+    let forward = &read_pair.0;
+    Some((
+        fastq::Record::with_attrs(
+            forward.id(),
+            forward.desc(),
+            &[&forward.seq()[..umi_start], umi.as_slice(), &forward.seq()[template_start..]].concat(),
+            &[&forward.qual()[..umi_end], &forward.qual()[template_start..]].concat(),
+        ),
+        read_pair.1.clone()
+    ))
+}
+
+// the UMI to bin a pair under when binning as pairs are read, adding it to the index if it starts a bin. a UMI with
+// wildcards (N or low-quality bases) only stands for its bin until a plain UMI joins it: the bin is then moved to that
+// UMI, so the key and tags don't depend on which of the two happened to be read first
+fn index_umi(umi_index: &mut UMIIndex, pair_handler: &mut PairHandler, barcode: &UMIVec, umi: &UMIVec) -> UMIVec {
+    match umi_index.find_nearest(umi).cloned() {
+        None => {
+            umi_index.insert(umi);
+            umi.clone()
+        }
+        Some(found) if found.contains(&b'N') && !umi.contains(&b'N') => {
+            umi_index.remove(&found);
+            umi_index.insert(umi);
+            pair_handler.rebin(&(barcode.clone(), found), &(barcode.clone(), umi.clone()));
+            umi.clone()
+        }
+        Some(found) => found,
+    }
+}
+
+// the entries before the knee of the counts' cumulative curve, most abundant first; the whitelist and every count are
+// written out if asked for. `noun` names what was counted in messages and in the counts' header
+fn infer_whitelist(counts: HashMap<UMIVec, usize>, noun: (&str, &str), whitelist_path: Option<&PathBuf>,
+                   counts_path: Option<&PathBuf>) -> Result<Vec<UMIVec>, GrebeError> {
+    let counts = counts.into_iter()
+        .sorted_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)))
+        .collect::<Vec<_>>();
+    let knee = knee_point(&counts.iter().map(|(_, count)| *count).collect::<Vec<_>>());
+    eprintln!("inferred {} from {} at the knee",
+              pluralize(&format!("whitelisted {}", noun.0), knee as isize, true),
+              pluralize(&format!("distinct {}", noun.0), counts.len() as isize, true));
+
+    // only real files can fail to be written, so there's always a path to blame
+    let whitelist_error = |err| GrebeError::Write(whitelist_path.cloned().unwrap_or_default(), err);
+    let counts_error = |err| GrebeError::Write(counts_path.cloned().unwrap_or_default(), err);
+    let mut whitelist_writer = writer::text_writer_from_path(whitelist_path)?;
+    let mut counts_writer = writer::text_writer_from_path(counts_path)?;
+    writeln!(counts_writer, "{}\tcount\twhitelisted", noun.1).map_err(counts_error)?;
+    for (index, (entry, count)) in counts.iter().enumerate() {
+        let entry = String::from_utf8_lossy(entry);
+        if index < knee {
+            writeln!(whitelist_writer, "{entry}\t{count}").map_err(whitelist_error)?;
+        }
+        writeln!(counts_writer, "{entry}\t{count}\t{}", index < knee).map_err(counts_error)?;
+    }
+    whitelist_writer.flush().map_err(whitelist_error)?;
+    counts_writer.flush().map_err(counts_error)?;
+
+    Ok(counts.into_iter().take(knee).map(|(entry, _)| entry).collect())
+}
+
+// what happened to the pairs one handler was given
+fn report(pair_handler: &PairHandler, umi_length: u8, verbs: (&str, &str)) {
+    if pair_handler.records_unpaired.0 > 0 {
+        println!("{} {}", verbs.0,
+                 pluralize("unpaired forward read", pair_handler.records_unpaired.0 as isize, true));
+    }
+    if pair_handler.records_unpaired.1 > 0 {
+        println!("{} {}", verbs.1,
+                 pluralize("unpaired reverse read", pair_handler.records_unpaired.1 as isize, true));
+    }
+
+    let total_dropped = pair_handler.pair_drop_reason_count.total();
+    println!("dropped {} for the following reasons:\n{}",
+             pluralize("pair", total_dropped as isize, true),
+             pair_handler.pair_drop_reason_count);
+
+    if umi_length > 0 && pair_handler.mark_duplicates {
+        // every pair is still held, so the families are all there is to report
+        println!("marked {} of {} as duplicates in {}; writing to disk...",
+                 pluralize("pair", pair_handler.duplicates_marked() as isize, true),
+                 pluralize("remaining pair", (pair_handler.records_total - total_dropped) as isize, true),
+                 pluralize("UMI family", pair_handler.families.len() as isize, true));
+    } else if umi_length > 0 {
+        if pair_handler.records_written > 0 {
+            // assumption: records_written = records_good
+            // this is valid in the current design where all good pairs are already written to disk at this point
+            // said pairs have already been "forgotten", so there is no further information to report
+            println!("filtered {} to {} via UMI, wrote to disk",
+                     pluralize("remaining pair", (pair_handler.records_total - total_dropped) as isize, true),
+                     pluralize("pair", pair_handler.records_written as isize, true));
+        } else {
+            // records_good is not populated, but number of bins is
+            println!("filtered {} down to {} via UMI; writing to disk...",
+                     pluralize("remaining pair", (pair_handler.records_total - total_dropped) as isize, true),
+                     pluralize("pair", pair_handler.umi_bins.len() as isize, true));
+        }
+    } else {
+        // everything that survived pair filtering went straight to disk (no UMI matching)
+        println!("wrote {} after pair-level filtering",
+                 pluralize("remaining pair", pair_handler.records_written as isize, true));
+    }
+}
 
 fn main() {
+    if let Err(err) = run() {
+        eprintln!("{err}");
+        exit(err.exit_code());
+    }
+}
+
+fn run() -> Result<(), GrebeError> {
     let cmd = clap::command!("grebe")
         .about("Processing tool for Illumina sequencing data")
+        .after_help("Exit codes:\n  \
+        0  success\n  \
+        2  bad arguments, including primers that aren't IUPAC DNA\n  \
+        3  unusable whitelist or sample sheet\n  \
+        4  an input or output couldn't be opened or read (retryable)\n  \
+        5  refused to overwrite a nonempty output\n  \
+        6  malformed record, or forward and reverse files out of sync\n  \
+        7  an input ends partway through a record, e.g. a truncated gzip (retryable once complete)\n  \
+        8  an output couldn't be written, e.g. a full disk (retryable)")
         .arg(clap::arg!(<"in-forward"> "forward (5'-3') reads to work with")
             .value_name("input forward .fastq")
             .value_parser(clap::value_parser!(PathBuf))
@@ -95,6 +402,62 @@ fn main() {
             .value_parser(0..=15)
             .required(false)
             .default_value("0"))
+        .arg(clap::arg!(-'b' <"barcode length"> "cell barcode length (these bases come off forward reads before the \
+        UMI; pairs are only ever binned with pairs from the same cell)")
+            .id("barcode-length")
+            .visible_alias("barcode-length")
+            .visible_alias("cb-length")
+            .value_parser(0..=32)
+            .required(false)
+            .default_value("0"))
+        .arg(clap::arg!(--"barcode-whitelist" <"path"> "file of known cell barcodes, one per line; correct each \
+        barcode to the one whitelisted barcode within --barcode-radius, and drop pairs with no or more than one \
+        closest match")
+            .visible_alias("cb-whitelist")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .required(false))
+        .arg(clap::arg!(--"barcode-radius" <"radius"> "correct cell barcodes at most this Hamming distance from a \
+        whitelisted barcode")
+            .value_parser(0..=15)
+            .required(false)
+            .default_value("1"))
+        .arg(clap::arg!(--"infer-whitelist" "count cell barcodes in a first pass, whitelist those before the knee of \
+        the cumulative count curve (as umi_tools whitelist does), then correct to that whitelist as \
+        --barcode-whitelist would")
+            .visible_alias("knee")
+            .conflicts_with("barcode-whitelist"))
+        .arg(clap::arg!(--"inferred-whitelist" <"path"> "where to write the whitelist --infer-whitelist settled on, \
+        one cell barcode and its count per line (usable as --barcode-whitelist later)")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .requires("infer-whitelist")
+            .required(false))
+        .arg(clap::arg!(--"whitelist-counts" <"path"> "where to write a TSV of every cell barcode --infer-whitelist \
+        counted and whether it was whitelisted")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .requires("infer-whitelist")
+            .required(false))
+        .arg(clap::arg!(--"sample-sheet" <"path"> "demultiplex on barcodes: one sample per line, its name, forward \
+        read (or i7) barcode and (optionally) reverse read (or i5) barcode, - for none. each sample gets its own \
+        outputs (named <sample>.<output>) and UMI deduplication")
+            .visible_alias("samples")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .required(false))
+        .arg(clap::arg!(--"demux-on" <"source"> "where the --sample-sheet barcodes are found")
+            .value_parser(clap::value_parser!(DemuxSource))
+            .requires("sample-sheet")
+            .required(false)
+            .default_value("inline"))
+        .arg(clap::arg!(--"demux-mismatches" <"count"> "assign pairs to the sample whose barcodes are at most this \
+        Hamming distance from theirs")
+            .value_parser(0..=15)
+            .required(false)
+            .default_value("1"))
+        .arg(clap::arg!(--"tag-headers" "add the cell barcode and UMI each pair was binned under to its read \
+        descriptions as SAM-style CB:Z: and UB:Z: tags (which aligners such as bwa -C and STAR can carry into BAMs)"))
         .arg(clap::arg!(--"collision-resolution-mode" <"mode"> "choose how to resolve UMI collisions")
             .alias("collision-resolution-method")
             .alias("conflict-resolution-mode")
@@ -102,14 +465,152 @@ fn main() {
             .visible_alias("crm")
             .value_parser(clap::value_parser!(UMICollisionResolutionMethod))
             .default_value("keep-first"))
-        .arg(clap::arg!(--"hr" <"hamming radius"> "bin UMIs together if at most this Hamming distance apart \
-        (good for small library error tolerance, but very slow on genomic-scale data)")
+        .arg(clap::arg!(--"quality-score" <"score"> "with --crm highest-quality, how to score a pair's base qualities")
+            .value_parser(clap::value_parser!(QualityScore))
+            .default_value("mean"))
+        .arg(clap::arg!(--"quality-tie-break" <"rule"> "with --crm highest-quality, which of two pairs scoring the \
+        same to keep")
+            .value_parser(clap::value_parser!(QualityTieBreak))
+            .default_value("first"))
+        .arg(clap::arg!(--"choose-mates-separately" "with a keep-longest --crm or highest-quality, keep the best \
+        forward read and the best reverse read of each family even if they are not mates, instead of whole pairs")
+            .visible_alias("per-mate")
+            .alias("score-mates-separately"))
+        .arg(clap::arg!(--"extend-mismatch-budget" <"phred"> "with --crm keep-longest-extend, still take a read \
+        as extending another if the Phred qualities of the bases they disagree on (the lower of the two at each, but \
+        at least 1) sum to at most this, so one sequencing error doesn't block an extension")
+            .value_parser(clap::value_parser!(u32))
+            .default_value("0"))
+        .arg(clap::arg!(--"extend-tie-break" <"rule"> "with --crm keep-longest-extend, what to do with a pair the \
+        same length as the one kept")
+            .value_parser(clap::value_parser!(ExtendTieBreak))
+            .default_value("first"))
+        .arg(clap::arg!(--"majority-tie-break" <"rule"> "with --crm majority, which of two sequences seen as often \
+        as each other to keep")
+            .value_parser(clap::value_parser!(MajorityTieBreak))
+            .default_value("first"))
+        .arg(clap::arg!(--"mark-duplicates" "as Picard MarkDuplicates does, write every pair instead of one per UMI \
+        family, adding the family's number (MI:Z:), cell barcode and UMI (CB:Z:, UB:Z:) and size (cD:i:) to read \
+        descriptions, and XD:i:1 to every pair other than the one --crm would have kept (XD:i:0); holds every pair in \
+        memory until the end")
+            .visible_alias("mark-dups"))
+        .arg(clap::arg!(--"max-consensus-quality" <"phred"> "with --crm quality-vote, cap consensus base qualities \
+        at this (and at whatever the quality encoding can hold)")
+            .visible_alias("max-cq")
+            .value_parser(clap::value_parser!(u8))
+            .default_value("90"))
+        .arg(clap::arg!(--"pre-umi-error-rate" <"phred"> "with --crm quality-vote, the Phred-scaled rate of errors \
+        made before UMIs were attached (e.g. DNA damage), which no number of agreeing reads can rule out")
+            .value_parser(clap::value_parser!(u8))
+            .required(false))
+        .arg(clap::arg!(--"post-umi-error-rate" <"phred"> "with --crm quality-vote, the Phred-scaled rate of errors \
+        made after UMIs were attached (e.g. in PCR), on top of what each base's quality says")
+            .value_parser(clap::value_parser!(u8))
+            .required(false))
+        .arg(clap::arg!(--"min-family-size" <"pairs"> "with --crm quality-vote, only write a consensus for UMI \
+        families of at least this many pairs")
+            .visible_alias("min-reads")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("1"))
+        .arg(clap::arg!(--"min-agreement" <"fraction"> "with --crm quality-vote, write N wherever less than this \
+        fraction of the family's reads with a base there agree with the consensus")
+            .value_parser(parse_fraction)
+            .default_value("0"))
+        .arg(clap::arg!(--"min-consensus-quality" <"phred"> "with --crm quality-vote, write N wherever the \
+        consensus quality would be below this")
+            .visible_alias("min-cq")
+            .value_parser(clap::value_parser!(u8))
+            .default_value("0"))
+        .arg(clap::arg!(--"align-consensus" "with --crm quality-vote, align each read to the first of its family \
+        before voting, so that an indel (e.g. a homopolymer miscount) doesn't throw off every base after it; bases \
+        most of the family has an extra copy of, or lacks, are added to or taken out of the consensus"))
+        .arg(clap::arg!(--"duplex" "with --crm quality-vote, read each UMI as two halves, A then B, and combine the \
+        consensuses of families AB and BA (the two strands of one molecule) into a duplex consensus, masking \
+        positions where they disagree"))
+        .arg(clap::arg!(--"duplex-layout" <"layout"> "with --duplex, where the two halves of the UMI (-u bases in \
+        all) are read")
+            .value_parser(clap::value_parser!(DuplexLayout))
+            .requires("duplex")
+            .required(false)
+            .default_value("split"))
+        .arg(clap::arg!(--"single-strand-forward" <"path"> "with --duplex, where to place forward reads of \
+        single-strand consensuses whose other strand had no family")
+            .value_name("output single-strand forward .fastq")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .requires("duplex")
+            .required(false))
+        .arg(clap::arg!(--"single-strand-reverse" <"path"> "with --duplex, where to place reverse reads of \
+        single-strand consensuses whose other strand had no family")
+            .value_name("output single-strand reverse .fastq")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .requires("duplex")
+            .required(false))
+        .arg(clap::arg!(--"vote-tallies" <"path"> "with --crm quality-vote, where to write a TSV of what each \
+        consensus base was voted from: one row per family, read and position, with the summed qualities of the reads \
+        calling A, T, C and G, the read depth and the consensus base")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .required(false))
+        .arg(clap::arg!(--"hr" <"hamming radius"> "bin UMIs together if at most this distance apart, Hamming \
+        unless --umi-distance says otherwise (good for small library error tolerance; radii past 3 get slow on \
+        genomic-scale data)")
             .id("hamming-radius")
             .visible_alias("hamming")
             .visible_alias("hamming-radius")
             .value_parser(0..=15)
             .required(false)
             .default_value("0"))
+        .arg(clap::arg!(--"umi-min-quality" <"quality"> "treat UMI bases below this quality, like Ns, as wildcards \
+        matching any base when binning")
+            .visible_alias("umi-quality")
+            .value_parser(0..=93)
+            .required(false)
+            .default_value("10"))
+        .arg(clap::arg!(--"max-umi-wildcards" <"count"> "drop pairs with more than this many N or low-quality bases \
+        in their UMI")
+            .visible_alias("max-umi-ns")
+            // every wildcard quadruples the lookups needed to find a UMI's neighbours
+            .value_parser(0..=6)
+            .required(false)
+            .default_value("2"))
+        .arg(clap::arg!(--"umi-distance" <"metric"> "how to measure how far apart two UMIs are for --hr")
+            .visible_alias("distance")
+            .value_parser(clap::value_parser!(UMIDistance))
+            .required(false)
+            .default_value("hamming"))
+        .arg(clap::arg!(--"umi-clustering" <"method"> "count every UMI in a first pass and cluster them before binning \
+        any pairs, so results do not depend on read order")
+            .visible_alias("cluster")
+            .value_parser(clap::value_parser!(UMIClusteringMethod))
+            .required(false)
+            .default_value("none"))
+        .arg(clap::arg!(--"umi-whitelist" <"path"> "file of known UMIs, one per line; correct each UMI to the one \
+        whitelisted UMI within --hr (default 1) instead of binning UMIs de novo, and drop pairs with no or more than \
+        one closest match")
+            .visible_alias("whitelist")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .conflicts_with("umi-clustering")
+            .required(false))
+        .arg(clap::arg!(--"infer-umi-whitelist" "count UMIs in a first pass, whitelist those before the knee of the \
+        cumulative count curve (summed over cell barcodes), then correct to that whitelist as --umi-whitelist would")
+            .conflicts_with_all(["umi-whitelist", "umi-clustering"]))
+        .arg(clap::arg!(--"inferred-umi-whitelist" <"path"> "where to write the whitelist --infer-umi-whitelist \
+        settled on, one UMI and its count per line (usable as --umi-whitelist later)")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .requires("infer-umi-whitelist")
+            .required(false))
+        .arg(clap::arg!(--"umi-whitelist-counts" <"path"> "where to write a TSV of every UMI --infer-umi-whitelist \
+        counted and whether it was whitelisted")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .requires("infer-umi-whitelist")
+            .required(false))
+        .arg(clap::arg!(--"umi-quality-ranking" "with --umi-clustering, also total UMI base qualities in the first \
+        pass and prefer the higher quality UMI as a cluster's representative when counts tie"))
         .arg(clap::arg!(--"proactive-binning" <"force mode"> "(for advanced users, see docs; you shouldn't have to \
         set this)")
             .visible_alias("pb")
@@ -134,6 +635,58 @@ fn main() {
             .value_parser(0..=600)
             .required(false)
             .default_value("0"))
+        .arg(clap::arg!(--"repair" "instead of requiring the nth forward and reverse records be mates, match records \
+        up by name (ignoring /1, /2 and descriptions) as BBTools' repair.sh does; records whose mate doesn't turn up \
+        go to the unpaired outputs")
+            .visible_alias("re-pair"))
+        .arg(clap::arg!(--"repair-buffer" <"records"> "with --repair, give up on a record's mate once this many \
+        records from the same file are waiting for theirs")
+            .value_parser(clap::value_parser!(usize))
+            .requires("repair")
+            .required(false)
+            .default_value("1000000"))
+        .arg(clap::arg!(--"lenient" "set aside records that are malformed (qualities out of range, sequence and \
+        qualities of different lengths, and bases other than ACGTN, which are otherwise read as N) and carry on, \
+        instead of stopping at the first; their mates go to the unpaired outputs"))
+        .arg(clap::arg!(--"quarantine-forward" <"path"> "with --lenient, where to place malformed forward records")
+            .value_name("output quarantine forward .fastq")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .requires("lenient")
+            .required(false))
+        .arg(clap::arg!(--"quarantine-reverse" <"path"> "with --lenient, where to place malformed reverse records")
+            .value_name("output quarantine reverse .fastq")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .requires("lenient")
+            .required(false))
+        .arg(clap::arg!(--"keep-filtered" "keep pairs whose headers flag them as failing the instrument's chastity \
+        filter (1:Y:0:... in Casava 1.8 headers), which are dropped by default"))
+        .arg(clap::arg!(--"max-primer-fraction" <"fraction"> "drop pairs whose insert (found from mate overlap or \
+        adapter read-through) is at least this fraction primer sequence as primer dimers; requires --fp and --rp")
+            .visible_alias("dimer-fraction")
+            .value_parser(parse_fraction)
+            .required(false))
+        .arg(clap::arg!(--"min-insert" <"length"> "drop pairs whose insert is shorter than this as off-target products")
+            .visible_alias("min-insert-length")
+            .value_parser(clap::value_parser!(usize))
+            .required(false))
+        .arg(clap::arg!(--"max-insert" <"length"> "drop pairs whose insert is longer than this as off-target products")
+            .visible_alias("max-insert-length")
+            .value_parser(clap::value_parser!(usize))
+            .required(false))
+        .arg(clap::arg!(--"artifacts-forward" <"path"> "where to place forward reads of primer dimers and off-target \
+        products")
+            .value_name("output artifact forward .fastq")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .required(false))
+        .arg(clap::arg!(--"artifacts-reverse" <"path"> "where to place reverse reads of primer dimers and off-target \
+        products")
+            .value_name("output artifact reverse .fastq")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_hint(ValueHint::FilePath)
+            .required(false))
         .group(ArgGroup::new("left-slice")
             .arg("start-at"))
         .arg(clap::arg!(<"out-forward"> "where to place processed forward reads")  // TODO: output more sequence formats
@@ -162,37 +715,60 @@ fn main() {
     let args = cmd.get_matches();
 
     let umi_length = *args.get_one::<i64>("umi-length").unwrap() as u8;
+    let barcode_length = *args.get_one::<i64>("barcode-length").unwrap() as usize;
 
-    let collision_resolution_method;
-    if umi_length == 0 {
+    let collision_resolution_method = if umi_length == 0 {
         // silently override this; --crm is meaningless in this context
-        collision_resolution_method = UMICollisionResolutionMethod::None;
+        UMICollisionResolutionMethod::None
     } else {
-        collision_resolution_method = args.get_one::<UMICollisionResolutionMethod>("collision-resolution-mode")
-            .unwrap().to_owned();
-    }
+        args.get_one::<UMICollisionResolutionMethod>("collision-resolution-mode").unwrap().to_owned()
+    };
 
     // let start_index_arg = *args.get_one::<i64>("start-at").unwrap();
     // let start_index_rev = start_index_arg;
     // let start_index_fwr = max(start_index_arg, umi_length);
 
-    let hamming_radius = min(*args.get_one::<i64>("hamming-radius").unwrap() as u8, umi_length);
+    let mut hamming_radius = min(*args.get_one::<i64>("hamming-radius").unwrap() as u8, umi_length);
     if hamming_radius >= umi_length && args.value_source("hamming-radius") == Some(ValueSource::CommandLine) {
         eprintln!("warning: --hamming-max too high to be meaningful")
     }
 
+    let umi_distance = args.get_one::<UMIDistance>("umi-distance").unwrap().to_owned();
+
+    let umi_clustering_method = match umi_length {
+        0 => UMIClusteringMethod::None,
+        _ => args.get_one::<UMIClusteringMethod>("umi-clustering").unwrap().to_owned()
+    };
+    // clustering and whitelists need somewhere to look, so the usual --hr 0 would make them no-ops
+    if (umi_clustering_method != UMIClusteringMethod::None || args.contains_id("umi-whitelist")
+        || args.get_flag("infer-umi-whitelist")) && hamming_radius == 0
+        && args.value_source("hamming-radius") != Some(ValueSource::CommandLine) {
+        hamming_radius = min(1, umi_length);
+    }
+
+    if umi_clustering_method == UMIClusteringMethod::None && args.get_flag("umi-quality-ranking") {
+        eprintln!("warning: --umi-quality-ranking is meaningless without --umi-clustering")
+    }
+
+    let umi_shift = umi_distance.max_shift(hamming_radius as usize);
+
     // TODO: debug print here
-    let proactive_binning = match args.get_one::<bool>("proactive-hamming") {
+    let proactive_binning = match args.get_one::<bool>("proactive-binning") {
         Some(result) => {
             if umi_length == 0 {
                 eprintln!("warning: --proactive_binning is meaningless with no UMI")
             } else if hamming_radius == 0 {
                 eprintln!("warning: --proactive_binning is meaningless with -l 0")
+            } else if *result && umi_distance != UMIDistance::Hamming {
+                eprintln!("warning: --proactive_binning only generates substitutions; ignoring it for --umi-distance \
+                other than hamming")
             }
-            *result
+            *result && umi_distance == UMIDistance::Hamming
         }
         // --pl true's intelligent binning makes it much slower for --crm none
-        None => hamming_radius <= 3 && collision_resolution_method != UMICollisionResolutionMethod::None
+        // past radius 1, generating every neighbour costs more than asking the index
+        None => hamming_radius <= 1 && collision_resolution_method != UMICollisionResolutionMethod::None
+            && umi_distance == UMIDistance::Hamming
     };
 
     let phred_correction = match args.get_flag("phred64") {
@@ -200,14 +776,55 @@ fn main() {
         true => 64,
     };
 
+    let duplex = match args.get_flag("duplex") {
+        true if collision_resolution_method != UMICollisionResolutionMethod::QualityVote => {
+            eprintln!("warning: --duplex is meaningless without --crm quality-vote");
+            false
+        }
+        true if umi_length == 0 || !umi_length.is_multiple_of(2) => {
+            return Err(GrebeError::InvalidArguments(
+                "--duplex needs a UMI of two halves the same length, so an even -u".to_owned()));
+        }
+        duplex => duplex,
+    };
+    // bases of the UMI read from the start of the reverse read
+    let reverse_umi_length = match args.get_one::<DuplexLayout>("duplex-layout") {
+        Some(DuplexLayout::Split) if duplex => {
+            if umi_distance != UMIDistance::Hamming {
+                return Err(GrebeError::InvalidArguments(
+                    "--duplex-layout split reads half the UMI from each read, but --umi-distance levenshtein only \
+                    realigns forward reads; use --duplex-layout inline or --umi-distance hamming".to_owned()));
+            }
+            umi_length as usize / 2
+        }
+        _ => 0,
+    };
+
+    let mark_duplicates = match args.get_flag("mark-duplicates") {
+        true if umi_length == 0 => {
+            eprintln!("warning: --mark-duplicates is meaningless with no UMI");
+            false
+        }
+        true if matches!(collision_resolution_method,
+            UMICollisionResolutionMethod::None | UMICollisionResolutionMethod::QualityVote) => {
+            return Err(GrebeError::InvalidArguments(
+                "--mark-duplicates needs a --crm that keeps one of each family's pairs".to_owned()));
+        }
+        true if collision_resolution_method == UMICollisionResolutionMethod::KeepLongestExtend
+            && *args.get_one::<ExtendTieBreak>("extend-tie-break").unwrap() == ExtendTieBreak::Merge => {
+            return Err(GrebeError::InvalidArguments(
+                "--mark-duplicates can't flag a merged pair, which is none of the family's own; use another \
+                --extend-tie-break".to_owned()));
+        }
+        mark_duplicates => mark_duplicates,
+    };
+
     let check_iupac_dna = |p: &String| !dna::iupac_alphabet().is_word(p.as_bytes());
-    if args.get_one::<String>("forward-primer").is_some_and(check_iupac_dna) {
-        eprintln!("forward primer not valid IUPAC DNA alphabet; refusing");
-        exit(1);
+    if let Some(primer) = args.get_one::<String>("forward-primer").filter(|p| check_iupac_dna(p)) {
+        return Err(GrebeError::InvalidPrimer("forward", primer.clone()));
     }
-    if args.get_one::<String>("reverse-primer").is_some_and(check_iupac_dna) {
-        eprintln!("reverse primer not valid IUPAC DNA alphabet; refusing");
-        exit(1);
+    if let Some(primer) = args.get_one::<String>("reverse-primer").filter(|p| check_iupac_dna(p)) {
+        return Err(GrebeError::InvalidPrimer("reverse", primer.clone()));
     }
 
     let enforce_primers = (
@@ -215,200 +832,516 @@ fn main() {
         args.get_one::<String>("reverse-primer").map(|s| s.as_bytes())
     );
 
+    let pair_filter = PairFilter {
+        barcode_length,
+        drop_filtered: !args.get_flag("keep-filtered"),
+        umi_length: umi_length as usize,
+        reverse_umi_length,
+        umi_shift,
+        enforce_primers,
+        insert_filter: InsertFilter {
+            primers: match enforce_primers {
+                (Some(forward_primer), Some(reverse_primer)) => {
+                    Some((forward_primer.to_vec(), dna::revcomp(reverse_primer)))
+                }
+                _ => None
+            },
+            max_primer_fraction: args.get_one::<f64>("max-primer-fraction").copied(),
+            min_insert_length: args.get_one::<usize>("min-insert").copied(),
+            max_insert_length: args.get_one::<usize>("max-insert").copied(),
+        },
+        phred_correction,
+        umi_min_quality: *args.get_one::<i64>("umi-min-quality").unwrap() as u8,
+        max_umi_wildcards: *args.get_one::<i64>("max-umi-wildcards").unwrap() as usize,
+    };
+    if pair_filter.insert_filter.max_primer_fraction.is_some() && pair_filter.insert_filter.primers.is_none() {
+        eprintln!("warning: --max-primer-fraction is meaningless without both --fp and --rp")
+    }
+
+    // the start of the forward primer pins down where an indel-shifted UMI really ends
+    let umi_anchor = enforce_primers.0.map_or(&[][..], |primer| &primer[..min(primer.len(), 8)]);
+
     let input_paths = (
         args.get_one::<PathBuf>("in-forward"),
         args.get_one::<PathBuf>("in-reverse")
     );
-    let record_readers = make_reader_pair(input_paths, true);
-    let total_records = (record_readers.0.records().count(), record_readers.1.records().count());
-
-    let record_readers = make_reader_pair(input_paths, false);
-
-    let record_writers = OutputWriters {
-        paired: writer::make_writer_pair((
-            args.get_one::<PathBuf>("out-forward"),
-            args.get_one::<PathBuf>("out-reverse")
-        )),
-        unpaired: writer::make_writer_pair((
-            args.get_one::<PathBuf>("out-unpaired-forward"),
-            args.get_one::<PathBuf>("out-unpaired-reverse")
-        )),
+    let record_readers = make_reader_pair(input_paths, true)?;
+    let total_records = (
+        count_records(record_readers.0, input_paths.0)?,
+        count_records(record_readers.1, input_paths.1)?
+    );
+
+    // records with no mate are only worth going on with if they can be matched up by name
+    let repair_buffer = match args.get_flag("repair") {
+        true => Some(*args.get_one::<usize>("repair-buffer").unwrap()),
+        false => None,
     };
+    let lenient = args.get_flag("lenient");
+    let read_pairs = |silent: bool| -> Result<_, GrebeError> {
+        let record_readers = make_reader_pair(input_paths, silent)?;
+        Ok(MatePairer::new(
+            (record_readers.0.records(), record_readers.1.records()),
+            (input_paths.0.cloned().unwrap_or_default(), input_paths.1.cloned().unwrap_or_default()),
+            repair_buffer,
+            phred_correction,
+            lenient
+        ))
+    };
+    if total_records.0 != total_records.1 && repair_buffer.is_none() {
+        // the first pair whose names differ shows where the files part ways; only once the shorter file runs out with
+        // every pair matched are the counts all there is to go on
+        return Err(match read_pairs(true)?.find_map(Result::err) {
+            Some(GrebeError::MatesOutOfSync(record_number, _))
+                if record_number > min(total_records.0, total_records.1) => {
+                GrebeError::MateCountsDiffer(total_records.0, total_records.1)
+            }
+            Some(err) => err,
+            None => GrebeError::MateCountsDiffer(total_records.0, total_records.1),
+        });
+    }
 
-    let mut pair_handler = PairHandler {
-        record_writers,
-        collision_resolution_method,
-        records_total: max(total_records.0, total_records.1),
-        phred_correction,
-        ..Default::default()
+    let demultiplexer = match args.get_one::<PathBuf>("sample-sheet") {
+        Some(path_buf) => {
+            let samples = read_sample_sheet(path_buf).map_err(|err| GrebeError::Open(path_buf.clone(), err))?;
+            let demultiplexer = Demultiplexer::new(samples, *args.get_one::<i64>("demux-mismatches").unwrap() as usize)
+                .map_err(|err| GrebeError::InvalidList(path_buf.clone(), err))?;
+            eprintln!("loaded {}", pluralize("sample", demultiplexer.samples.len() as isize, true));
+            Some(demultiplexer)
+        }
+        None => None,
+    };
+    let demux_source = args.get_one::<DemuxSource>("demux-on").unwrap().to_owned();
+    // which sample a pair belongs to (always the one and only without a sample sheet)
+    let assign_sample = |read_pair: &FastqPair| -> Result<usize, DemuxMiss> {
+        match &demultiplexer {
+            None => Ok(0),
+            Some(demultiplexer) => match demux_source {
+                DemuxSource::Inline => demultiplexer.assign_inline(read_pair),
+                DemuxSource::Header => demultiplexer.assign_header(read_pair),
+            },
+        }
+    };
+    // the pair as the rest of processing should see it
+    let trim_sample_barcodes = |read_pair: FastqPair| match &demultiplexer {
+        Some(demultiplexer) if demux_source == DemuxSource::Inline => demultiplexer.trim_inline(&read_pair),
+        _ => read_pair,
+    };
+    let sample_names = match &demultiplexer {
+        None => vec![None],
+        Some(demultiplexer) => demultiplexer.samples.iter().map(|sample| Some(sample.name.as_str())).collect(),
+    };
+
+    let infer_barcode_whitelist = match args.get_flag("infer-whitelist") {
+        true if barcode_length == 0 => {
+            eprintln!("warning: --infer-whitelist is meaningless with -b 0");
+            false
+        }
+        infer => infer,
+    };
+    let barcode_radius = min(*args.get_one::<i64>("barcode-radius").unwrap() as usize, barcode_length);
+    let barcode_whitelist = match args.get_one::<PathBuf>("barcode-whitelist") {
+        Some(_) if barcode_length == 0 => {
+            eprintln!("warning: --barcode-whitelist is meaningless with -b 0");
+            None
+        }
+        Some(path_buf) => {
+            let entries = read_whitelist(path_buf).map_err(|err| GrebeError::Open(path_buf.clone(), err))?;
+            if let Some(entry) = entries.iter().find(|entry| entry.len() != barcode_length) {
+                return Err(GrebeError::InvalidList(path_buf.clone(), format!(
+                    "whitelisted cell barcode {} is not {} long", String::from_utf8_lossy(entry),
+                    pluralize("base", barcode_length as isize, true))));
+            }
+
+            eprintln!("loaded {}", pluralize("whitelisted cell barcode", entries.len() as isize, true));
+            Some(Whitelist::new(&entries, barcode_radius, UMIDistance::Hamming))
+        }
+        None if infer_barcode_whitelist => {
+            eprintln!("counting cell barcodes...");
+
+            let mut counts = HashMap::<UMIVec, usize>::new();
+            for mates in read_pairs(true)? {
+                // broken records and mismatched names are reported when the last pass reaches them
+                if let Ok(Mates::Pair(read_pair)) = mates {
+                    if assign_sample(&read_pair).is_err() {
+                        continue;
+                    }
+                    let read_pair = trim_sample_barcodes(read_pair);
+                    if matches!(pair_filter.screen(&read_pair), PairVerdict::Keep) {
+                        let barcode = pair_filter.barcode(&read_pair.0);
+                        // barcodes with wildcards can't be whitelisted, but will still be corrected to whatever is
+                        if !barcode.contains(&b'N') {
+                            *counts.entry(barcode).or_default() += 1;
+                        }
+                    }
+                }
+            }
+
+            let entries = infer_whitelist(counts, ("cell barcode", "barcode"),
+                                          args.get_one::<PathBuf>("inferred-whitelist"),
+                                          args.get_one::<PathBuf>("whitelist-counts"))?;
+            Some(Whitelist::new(&entries, barcode_radius, UMIDistance::Hamming))
+        }
+        None => None,
+    };
+    // the barcode a pair is binned under, corrected if there's a whitelist
+    let bin_barcode = |forward: &fastq::Record| -> Result<UMIVec, PairDropReason> {
+        let barcode = pair_filter.barcode(forward);
+        match &barcode_whitelist {
+            None => Ok(barcode),
+            Some(barcode_whitelist) => barcode_whitelist.correct(&barcode).map_err(|miss| match miss {
+                WhitelistMiss::Unmatched => PairDropReason::BarcodeNotWhitelisted,
+                WhitelistMiss::Ambiguous => PairDropReason::AmbiguousBarcodeMatch,
+            }),
+        }
+    };
+
+    // anything that needs to see every UMI before binning starts counts them here, in a first pass. samples are
+    // deduplicated separately, so each gets its own counts
+    let infer_umi_whitelist = umi_length > 0 && args.get_flag("infer-umi-whitelist");
+    let mut umi_clusterers = sample_names.iter()
+        .map(|_| UMIClusterer {
+            method: umi_clustering_method,
+            radius: hamming_radius as usize,
+            distance: umi_distance,
+            collect_qualities: args.get_flag("umi-quality-ranking"),
+            phred_correction,
+            tallies: Default::default(),
+        })
+        .collect::<Vec<_>>();
+    if umi_clustering_method != UMIClusteringMethod::None || infer_umi_whitelist {
+        eprintln!("counting UMIs...");
+
+        for mates in read_pairs(true)? {
+            // broken records and mismatched names are reported when the last pass reaches them
+            if let Ok(Mates::Pair(read_pair)) = mates {
+                let Ok(sample) = assign_sample(&read_pair) else {
+                    continue;
+                };
+                let read_pair = trim_sample_barcodes(read_pair);
+                if matches!(pair_filter.screen(&read_pair), PairVerdict::Keep) {
+                    if let Ok(barcode) = bin_barcode(&read_pair.0) {
+                        umi_clusterers[sample].count(&(barcode, pair_filter.umi(&read_pair)),
+                                                     &pair_filter.umi_qualities(&read_pair));
+                    }
+                }
+            }
+        }
+    }
+
+    let umi_whitelist = match args.get_one::<PathBuf>("umi-whitelist").filter(|_| umi_length > 0) {
+        Some(path_buf) => {
+            let entries = read_whitelist(path_buf).map_err(|err| GrebeError::Open(path_buf.clone(), err))?;
+            if let Some(entry) = entries.iter().find(|entry| entry.len() != umi_length as usize) {
+                return Err(GrebeError::InvalidList(path_buf.clone(), format!(
+                    "whitelisted UMI {} is not {} long", String::from_utf8_lossy(entry),
+                    pluralize("base", umi_length as isize, true))));
+            }
+
+            eprintln!("loaded {}", pluralize("whitelisted UMI", entries.len() as isize, true));
+            Some(Whitelist::new(&entries, hamming_radius as usize, umi_distance))
+        }
+        None if infer_umi_whitelist => {
+            // UMIs with wildcards can't be whitelisted, but will still be corrected to whatever is
+            let counts = umi_clusterers.iter()
+                .flat_map(|umi_clusterer| umi_clusterer.tallies.iter())
+                .filter(|((_, umi), _)| !umi.contains(&b'N'))
+                .map(|((_, umi), tally)| (umi.clone(), tally.count))
+                // a UMI's count under every cell barcode, in every sample
+                .into_grouping_map()
+                .sum();
+
+            let entries = infer_whitelist(counts, ("UMI", "umi"),
+                                          args.get_one::<PathBuf>("inferred-umi-whitelist"),
+                                          args.get_one::<PathBuf>("umi-whitelist-counts"))?;
+            Some(Whitelist::new(&entries, hamming_radius as usize, umi_distance))
+        }
+        None => None,
     };
 
-    eprintln!("counted {}, working...", pluralize("pair", pair_handler.records_total as isize, true));
-    let bar = ProgressBar::new(pair_handler.records_total as u64).with_finish(ProgressFinish::AndLeave);
+    let umi_representatives = match umi_clustering_method {
+        UMIClusteringMethod::None => None,
+        _ => {
+            let umi_representatives = umi_clusterers.iter().map(UMIClusterer::cluster).collect::<Vec<_>>();
+            let umi_count = umi_representatives.iter().map(HashMap::len).sum::<usize>();
+            let group_count = umi_representatives.iter()
+                .map(|representatives| representatives.iter()
+                    .map(|((barcode, _), representative)| (barcode, representative))
+                    .unique()
+                    .count())
+                .sum::<usize>();
+            eprintln!("clustered {} into {}",
+                      pluralize("distinct UMI", umi_count as isize, true),
+                      pluralize("group", group_count as isize, true));
+            Some(umi_representatives)
+        }
+    };
+
+    // with a sample sheet, every output is split into one per sample
+    let output_path = |id: &str, sample: Option<&str>| args.get_one::<PathBuf>(id)
+        .map(|path_buf| match sample {
+            None => path_buf.clone(),
+            Some(sample) => sample_path(path_buf, sample),
+        });
+    let make_output_writers = |sample: Option<&str>| -> Result<OutputWriters, GrebeError> {
+        Ok(OutputWriters {
+            paired: writer::make_writer_pair((
+                output_path("out-forward", sample).as_ref(),
+                output_path("out-reverse", sample).as_ref()
+            ))?,
+            unpaired: writer::make_writer_pair((
+                output_path("out-unpaired-forward", sample).as_ref(),
+                output_path("out-unpaired-reverse", sample).as_ref()
+            ))?,
+            artifact: writer::make_writer_pair((
+                output_path("artifacts-forward", sample).as_ref(),
+                output_path("artifacts-reverse", sample).as_ref()
+            ))?,
+            single_strand: writer::make_writer_pair((
+                output_path("single-strand-forward", sample).as_ref(),
+                output_path("single-strand-reverse", sample).as_ref()
+            ))?,
+            vote_tallies: writer::table_writer_from_path(output_path("vote-tallies", sample).as_ref())?,
+        })
+    };
 
-    let pairs = record_readers.0.records().zip(record_readers.1.records());
-    'pairs: for maybe_read_pair in pairs {
+    let mut pair_handlers = sample_names.iter()
+        .map(|sample| Ok(PairHandler {
+            record_writers: make_output_writers(*sample)?,
+            collision_resolution_method,
+            quality_ranking: QualityRanking {
+                score: args.get_one::<QualityScore>("quality-score").unwrap().to_owned(),
+                tie_break: args.get_one::<QualityTieBreak>("quality-tie-break").unwrap().to_owned(),
+                phred_correction,
+            },
+            extension: Extension {
+                mismatch_budget: *args.get_one::<u32>("extend-mismatch-budget").unwrap(),
+                tie_break: args.get_one::<ExtendTieBreak>("extend-tie-break").unwrap().to_owned(),
+                phred_correction,
+            },
+            majority_tie_break: args.get_one::<MajorityTieBreak>("majority-tie-break").unwrap().to_owned(),
+            per_mate: args.get_flag("choose-mates-separately"),
+            tag_headers: args.get_flag("tag-headers"),
+            phred_correction,
+            duplex,
+            reverse_umi_length,
+            mark_duplicates,
+            consensus_caller: ConsensusCaller {
+                min_family_size: *args.get_one::<usize>("min-family-size").unwrap(),
+                min_agreement: *args.get_one::<f64>("min-agreement").unwrap(),
+                min_quality: *args.get_one::<u8>("min-consensus-quality").unwrap(),
+                align: args.get_flag("align-consensus"),
+                ..ConsensusCaller::new(
+                    // ~ is the highest quality character there is
+                    min(*args.get_one::<u8>("max-consensus-quality").unwrap(), b'~' - phred_correction),
+                    args.get_one::<u8>("pre-umi-error-rate").copied(),
+                    args.get_one::<u8>("post-umi-error-rate").copied(),
+                )
+            },
+            ..Default::default()
+        }))
+        .collect::<Result<Vec<_>, GrebeError>>()?;
+    // pairs that belong to no sample are written out untouched
+    let mut undetermined = match &demultiplexer {
+        None => PairHandler::default(),
+        Some(_) => PairHandler {
+            record_writers: make_output_writers(Some(UNDETERMINED))?,
+            ..Default::default()
+        },
+    };
+    let mut demux_miss_count = DemuxMissCount::default();
+
+    let records_total = max(total_records.0, total_records.1);
+    eprintln!("counted {}, working...", pluralize("pair", records_total as isize, true));
+    let bar = ProgressBar::new(records_total as u64).with_finish(ProgressFinish::AndLeave);
+
+    // always kept up to date by non-proactive binning; proactive binning only needs it for UMIs with wildcards. one per
+    // cell barcode in each sample, since pairs from different cells never share a bin
+    let mut sample_umi_indices = sample_names.iter()
+        .map(|_| HashMap::<UMIVec, UMIIndex>::new())
+        .collect::<Vec<_>>();
+    let new_umi_index = || UMIIndex::new(umi_length as usize, hamming_radius as usize, umi_distance);
+    let track_wildcards = pair_filter.max_umi_wildcards > 0;
+
+    let mut quarantine_writers = writer::make_writer_pair((
+        args.get_one::<PathBuf>("quarantine-forward"),
+        args.get_one::<PathBuf>("quarantine-reverse")
+    ))?;
+    let mut record_fault_count = RecordFaultCount::default();
+
+    let mut orphans = (0, 0);
+    let mut mate_pairer = read_pairs(false)?;
+    'pairs: for mates in mate_pairer.by_ref() {
         bar.inc(1);
 
         // basic layout for this code:
-        // 1. verify validity of reads
+        // 1. verify validity of reads, and that they're mates
         // 2. verify validity of pair overall (paired/unpaired, sufficient length, primer, etc.)
         // 3. match UMI and allow handler struct to decide what to do from there
 
         // these checks permit me to go insane and unsafe every string parse
-        let read_pair = (
-            match maybe_read_pair.0 {
-                Ok(result) => match result.check() {
-                    Ok(_) => result,
-                    Err(err) => {
-                        eprintln!("forward record {} was invalid: {err}", pair_handler.records_total);
-                        exit(1);
-                    }
-                },
-                Err(_) => {
-                    eprintln!("forward record {} was invalid", pair_handler.records_total);
-                    exit(1);
+        let read_pair = match mates {
+            Ok(Mates::Pair(read_pair)) => read_pair,
+            Ok(Mates::Orphan(record, which_read)) => {
+                // no sample can be told from one read, so with a sample sheet these are undetermined
+                match which_read {
+                    WhichRead::FORWARD => orphans.0 += 1,
+                    WhichRead::REVERSE => orphans.1 += 1,
                 }
-            },
-            match maybe_read_pair.1 {
-                Ok(result) => match result.check() {
-                    Ok(_) => result,
-                    Err(err) => {
-                        eprintln!("reverse record {} was invalid: {err}", pair_handler.records_total);
-                        exit(1);
-                    }
-                },
-                Err(_) => {
-                    eprintln!("reverse record {} was invalid", pair_handler.records_total);
-                    exit(1);
+                match demultiplexer {
+                    None => pair_handlers[0].write_unpaired(record, which_read)?,
+                    Some(_) => undetermined.write_unpaired(record, which_read)?,
                 }
-            }
-        );
-
-        let n_closure = |s: &u8| *s == b'N';
-        match (read_pair.0.seq().iter().all(n_closure), read_pair.1.seq().iter().all(n_closure)) {
-            (true, false) => {
-                pair_handler.write_unpaired(read_pair.0, WhichRead::REVERSE);
-                continue 'pairs;
-            }
-            (false, true) => {
-                pair_handler.write_unpaired(read_pair.1, WhichRead::FORWARD);
                 continue 'pairs;
             }
-            (true, true) => {
-                pair_handler.pair_drop_reason_count.both_masked += 1;
+            Ok(Mates::Malformed(record, which_read, fault)) => {
+                record_fault_count.add(fault);
+                let quarantine_writer = match which_read {
+                    WhichRead::FORWARD => &mut quarantine_writers.0,
+                    WhichRead::REVERSE => &mut quarantine_writers.1,
+                };
+                quarantine_writer.write(record.id(), record.desc(), record.seq(), record.qual())?;
                 continue 'pairs;
             }
-            _ => {}
-        }
+            Err(err) => return Err(err),
+        };
 
-        if enforce_primers.0.is_some() {
-            if read_pair.0.seq().len() < umi_length as usize + enforce_primers.0.unwrap().len() {
-                pair_handler.pair_drop_reason_count.no_forward_primer += 1;
+        let sample = match assign_sample(&read_pair) {
+            Ok(sample) => sample,
+            Err(miss) => {
+                demux_miss_count.add(miss);
+                unsafe { undetermined.write_pair(read_pair)? };
                 continue 'pairs;
             }
+        };
+        let read_pair = trim_sample_barcodes(read_pair);
+        let pair_handler = &mut pair_handlers[sample];
+        pair_handler.records_total += 1;
+        let umi_indices = &mut sample_umi_indices[sample];
+        let umi_representatives = umi_representatives.as_ref().map(|representatives| &representatives[sample]);
 
-            let starts_with_primer = check_primer(enforce_primers.0.as_ref().unwrap(), &read_pair.0.seq())
-                .unwrap_or_default();
-            let starts_with_umi_then_primer = check_primer(
-                enforce_primers.0.as_ref().unwrap(),
-                &read_pair.0.seq()[umi_length as usize..],
-            ).unwrap_or_default();
-
-            if umi_length > 0 && starts_with_primer && !starts_with_umi_then_primer {
-                // very unlikely the UMI then following seq is the primer; we will call this a bad UMI addition
-                pair_handler.pair_drop_reason_count.umi_is_forward_primer += 1;
+        match pair_filter.screen(&read_pair) {
+            PairVerdict::Keep => {}
+            PairVerdict::Unpaired(which_read) => {
+                match which_read {
+                    WhichRead::FORWARD => pair_handler.write_unpaired(read_pair.0, which_read)?,
+                    WhichRead::REVERSE => pair_handler.write_unpaired(read_pair.1, which_read)?,
+                }
                 continue 'pairs;
-            } else if !starts_with_umi_then_primer {
-                // primer not present where it should be
-                pair_handler.pair_drop_reason_count.no_forward_primer += 1;
+            }
+            PairVerdict::Drop(reason) => {
+                pair_handler.pair_drop_reason_count.add(reason);
                 continue 'pairs;
             }
-        }
-        if enforce_primers.1.is_some() {
-            if read_pair.1.seq().len() < enforce_primers.1.unwrap().len() {
-                pair_handler.pair_drop_reason_count.no_reverse_primer += 1;
+            PairVerdict::Artifact(insert_class) => {
+                pair_handler.write_artifact(&read_pair, insert_class)?;
                 continue 'pairs;
             }
+        }
 
-            let starts_with_primer = check_primer(enforce_primers.1.as_ref().unwrap(), read_pair.1.seq())
-                .unwrap_or_default();
-
-            if !starts_with_primer {
-                pair_handler.pair_drop_reason_count.no_reverse_primer += 1;
+        let barcode = match bin_barcode(&read_pair.0) {
+            Ok(barcode) => barcode,
+            Err(reason) => {
+                pair_handler.pair_drop_reason_count.add(reason);
                 continue 'pairs;
             }
-        }
+        };
 
         if umi_length > 0 {
-            let umi: UMIVec = read_pair.0.seq()[..umi_length as usize].iter().copied().collect();
-            if hamming_radius == 0 {
-                pair_handler.insert_pair(&umi, &read_pair);
-            } else {
-                if pair_handler.umi_bins.contains_key(&umi) {
-                    pair_handler.insert_pair(&umi, &read_pair);
-                    continue 'pairs;
+            let umi = pair_filter.umi(&read_pair);
+            let has_wildcards = umi.contains(&b'N');
+            let realign = |read_pair: &FastqPair, umi: &UMIVec| {
+                realign_to_umi(read_pair, umi, barcode_length, umi_anchor, umi_shift)
+            };
+            if let Some(umi_whitelist) = &umi_whitelist {
+                // the whitelist is the only set of bins there is
+                match umi_whitelist.correct(&umi) {
+                    Ok(corrected) => match realign(&read_pair, &corrected) {
+                        None => pair_handler.insert_pair(&(barcode, corrected), &read_pair)?,
+                        Some(realigned) => pair_handler.insert_pair(&(barcode, corrected), &realigned)?,
+                    },
+                    Err(miss) => pair_handler.pair_drop_reason_count.add(match miss {
+                        WhitelistMiss::Unmatched => PairDropReason::NotWhitelisted,
+                        WhitelistMiss::Ambiguous => PairDropReason::AmbiguousWhitelistMatch,
+                    }),
+                }
+            } else if let Some(umi_representatives) = umi_representatives {
+                // clustering already decided where every UMI goes
+                let key = (barcode, umi);
+                match umi_representatives.get(&key) {
+                    Some(representative) => match realign(&read_pair, representative) {
+                        None => pair_handler.insert_pair(&(key.0, representative.clone()), &read_pair)?,
+                        Some(realigned) => pair_handler.insert_pair(&(key.0, representative.clone()), &realigned)?,
+                    },
+                    None => pair_handler.pair_drop_reason_count.add(PairDropReason::RareUMI),
                 }
+            } else if pair_handler.umi_bins.contains_key(&(barcode.clone(), umi.clone()))
+                || (hamming_radius == 0 && !track_wildcards) {
+                pair_handler.insert_pair(&(barcode, umi), &read_pair)?;
+            } else if proactive_binning && hamming_radius > 0 && !has_wildcards {
+                // instead of checking the distance to elements of the set of known UMIs,
+                // generate UMIs within a certain distance and check them
+                // (wildcards would need every base at their position generated too, so those go to the index)
 
-                if proactive_binning {
-                    // instead of checking the distance to elements of the set of known UMIs,
-                    // generate UMIs within a certain distance and check them
-                    // TODO: assumes no Ns outside of masked reads
-
-                    let mut found_bins = HashSet::new();
-
-                    // first, generate all options for <hamming_max> new base values
-                    let new_bases = std::iter::repeat("ATCG".chars())
-                        .take(hamming_radius as usize)
-                        .multi_cartesian_product();
-                    // then, generate all options for <hamming_radius> positions to replace at
-                    for indices_to_replace in (0..umi_length).combinations(hamming_radius as usize) {
-                        // execute the replacement
-                        for base_substitution in new_bases.clone() {
-                            let mut umi_modified = umi.clone();
-                            for (index, new_value) in (&indices_to_replace).iter().zip(base_substitution) {
-                                umi_modified[*index as usize] = new_value as u8;
-                            }
-
-                            if collision_resolution_method == UMICollisionResolutionMethod::None {
-                                found_bins.insert(umi_modified);
-                            } else {
-                                if pair_handler.umi_bins.contains_key(&umi_modified) {
-                                    pair_handler.insert_pair(&umi_modified, &read_pair);
-                                    continue 'pairs;
-                                }
-                            }
+                let mut found_bins = HashSet::new();
+
+                // first, generate all options for <hamming_max> new base values
+                let new_bases = std::iter::repeat_n("ATCG".chars(), hamming_radius as usize)
+                    .multi_cartesian_product();
+                // then, generate all options for <hamming_radius> positions to replace at
+                for indices_to_replace in (0..umi_length).combinations(hamming_radius as usize) {
+                    // execute the replacement
+                    for base_substitution in new_bases.clone() {
+                        let mut umi_modified = umi.clone();
+                        for (index, new_value) in indices_to_replace.iter().zip(base_substitution) {
+                            umi_modified[*index as usize] = new_value as u8;
                         }
-                    }
+                        let key_modified = (barcode.clone(), umi_modified);
 
-                    // we reach this if:
-                    // no known UMI was suitable
-                    // or if --crm none and at least one known UMI was suitable
-                    match found_bins.into_iter()
-                        .max_by_key(|k| match pair_handler.umi_bins.get(k) {
-                            None => 0,
-                            Some(s) => s.len()
-                        }) {
-                        // if the first case above is true, the iterator stops immediately and we accept a new UMI
-                        None => pair_handler.insert_pair(&umi, &read_pair),
-                        // if the second case is true, we have found a "best" UMI (defined as the UMI with the biggest
-                        // bin) and we use that one
-                        Some(umi_modified) => pair_handler.insert_pair(&umi_modified, &read_pair)
+                        if collision_resolution_method == UMICollisionResolutionMethod::None {
+                            found_bins.insert(key_modified);
+                        } else if pair_handler.umi_bins.contains_key(&key_modified) {
+                            pair_handler.insert_pair(&key_modified, &read_pair)?;
+                            continue 'pairs;
+                        }
                     }
-                } else {
-                    // non-proactive mode; just check every known UMI and see if it's close enough
+                }
 
-                    match find_within_radius(&pair_handler.umi_bins, &umi, hamming_radius as usize) {
-                        None => pair_handler.insert_pair(&umi, &read_pair),
-                        Some(found) => pair_handler.insert_pair(&found, &read_pair)
-                    }
+                // we reach this if:
+                // no known UMI was suitable
+                // or if --crm none and at least one known UMI was suitable
+                match found_bins.into_iter()
+                    .max_by_key(|k| match pair_handler.umi_bins.get(k) {
+                        None => 0,
+                        Some(s) => s.len()
+                    }) {
+                    // if the first case above is true, the iterator stops immediately and we accept a new UMI,
+                    // unless an earlier UMI with wildcards already covers it
+                    None => match track_wildcards {
+                        true => {
+                            let umi_index = umi_indices.entry(barcode.clone()).or_insert_with(new_umi_index);
+                            let found = index_umi(umi_index, pair_handler, &barcode, &umi);
+                            pair_handler.insert_pair(&(barcode, found), &read_pair)?
+                        }
+                        false => pair_handler.insert_pair(&(barcode, umi), &read_pair)?,
+                    },
+                    // if the second case is true, we have found a "best" UMI (defined as the UMI with the biggest
+                    // bin) and we use that one
+                    Some(key_modified) => pair_handler.insert_pair(&key_modified, &read_pair)?
+                }
+            } else {
+                // non-proactive mode; ask the index for the closest known UMI that's close enough
+
+                let umi_index = umi_indices.entry(barcode.clone()).or_insert_with(new_umi_index);
+                let found = index_umi(umi_index, pair_handler, &barcode, &umi);
+                match realign(&read_pair, &found).filter(|_| found != umi) {
+                    None => pair_handler.insert_pair(&(barcode, found), &read_pair)?,
+                    Some(realigned) => pair_handler.insert_pair(&(barcode, found), &realigned)?,
                 }
             }
         } else {
-            pair_handler.insert_pair(&vec![], &read_pair);
+            pair_handler.insert_pair(&(barcode, vec![]), &read_pair)?;
         }
     }
-
     bar.finish_using_style();
 
     let saved_verb = "wrote";
@@ -422,43 +1355,80 @@ fn main() {
             false => dropped_verb,
         }
     );
-    if pair_handler.records_unpaired.0 > 0 {
-        println!("{} {}", verbs.0,
-                 pluralize("unpaired forward read", pair_handler.records_unpaired.0 as isize, true));
+
+    if repair_buffer.is_some() {
+        println!("re-paired reads by name; {} and {} had no mate and went to the unpaired outputs",
+                 pluralize("forward read", orphans.0 as isize, true),
+                 pluralize("reverse read", orphans.1 as isize, true));
+    } else if lenient {
+        println!("{} and {} lost their mate to quarantine and went to the unpaired outputs",
+                 pluralize("forward read", orphans.0 as isize, true),
+                 pluralize("reverse read", orphans.1 as isize, true));
     }
-    if pair_handler.records_unpaired.1 > 0 {
-        println!("{} {}", verbs.1,
-                 pluralize("unpaired reverse read", pair_handler.records_unpaired.1 as isize, true));
+    if lenient {
+        let verb = match args.get_one::<PathBuf>("quarantine-forward").is_some()
+            || args.get_one::<PathBuf>("quarantine-reverse").is_some() {
+            true => "quarantined",
+            false => "skipped",
+        };
+        println!("{verb} {} for the following reasons:\n{}",
+                 pluralize("malformed record", record_fault_count.total() as isize, true),
+                 record_fault_count);
+        quarantine_writers.0.flush()?;
+        quarantine_writers.1.flush()?;
+    }
+    if mate_pairer.masked.unexpected_base > 0 {
+        println!("read bases other than A, C, G, T or N as N in {}",
+                 pluralize("record", mate_pairer.masked.unexpected_base as isize, true));
     }
 
-    let total_dropped = pair_handler.pair_drop_reason_count.total();
-    println!("dropped {} for the following reasons:\n{}",
-             pluralize("pair", total_dropped as isize, true),
-             pair_handler.pair_drop_reason_count);
+    for (sample, pair_handler) in sample_names.iter().zip(pair_handlers.iter_mut()) {
+        if let Some(sample) = sample {
+            println!("\nsample {sample}:");
+        }
+        report(pair_handler, umi_length, verbs);
+        pair_handler.write_remaining()?;
+        if collision_resolution_method == UMICollisionResolutionMethod::QualityVote {
+            println!("built consensus pairs from UMI families; of those:\n{}", pair_handler.consensus_count);
+        }
+        if duplex {
+            println!("{}", pair_handler.duplex_count);
+        }
+        if matches!(collision_resolution_method, UMICollisionResolutionMethod::KeepLongestLeft |
+            UMICollisionResolutionMethod::KeepLongestRight | UMICollisionResolutionMethod::KeepLongestExtend |
+            UMICollisionResolutionMethod::KeepHighestQuality) && !mark_duplicates {
+            println!("forward and reverse reads would have kept different pairs in {}; {}",
+                     pluralize("collision", pair_handler.mate_disagreements as isize, true),
+                     match pair_handler.per_mate {
+                         true => "kept each read's choice, so some output pairs are not mates",
+                         false => "kept whole pairs",
+                     });
+        }
+    }
 
-    if umi_length > 0 {
-        if pair_handler.records_written > 0 {
-            // assumption: records_written = records_good
-            // this is valid in the current design where all good pairs are already written to disk at this point
-            // said pairs have already been "forgotten", so there is no further information to report
-            println!("filtered {} to {} via UMI, wrote to disk",
-                     pluralize("remaining pair", (pair_handler.records_total - total_dropped) as isize, true),
-                     pluralize("pair", pair_handler.records_written as isize, true));
-        } else {
-            // records_good is not populated, but number of bins is
-            println!("filtered {} down to {} via UMI; writing to disk...",
-                     pluralize("remaining pair", (pair_handler.records_total - total_dropped) as isize, true),
-                     pluralize("pair", pair_handler.umi_bins.len() as isize, true));
+    if demultiplexer.is_some() {
+        println!("\nwrote {} matching no sample as {UNDETERMINED} for the following reasons:\n{}",
+                 pluralize("pair", demux_miss_count.undetermined() as isize, true),
+                 demux_miss_count);
+
+        let combinations = pluralize("pair", demux_miss_count.combination as isize, true);
+        match demux_source {
+            DemuxSource::Inline => println!("wrote {combinations} with barcodes from different samples as \
+            {UNDETERMINED}"),
+            DemuxSource::Header => {
+                // of the pairs with a valid i7 and a valid i5, how many had them from different samples
+                let assigned = pair_handlers.iter().map(|pair_handler| pair_handler.records_total).sum::<usize>();
+                let hopped_fraction = demux_miss_count.combination as f64
+                    / max(assigned + demux_miss_count.combination, 1) as f64;
+                println!("wrote {combinations} with one sample's i7 and another's i5 (index hopping, {:.3}% of pairs \
+                with known indexes) as {UNDETERMINED}", hopped_fraction * 100.0)
+            }
         }
-    } else {
-        // everything that survived pair filtering went straight to disk (no UMI matching)
-        println!("wrote {} after pair-level filtering",
-                 pluralize("remaining pair", pair_handler.records_written as isize, true));
     }
 
-    pair_handler.write_remaining();
+    undetermined.record_writers.flush()?;
 
     // TODO: verbose logging (masked reads, etc.)
-    // TODO: exit codes
     // TODO: do things on quality scores
+    Ok(())
 }
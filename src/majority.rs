@@ -0,0 +1,73 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use strum::VariantArray;
+
+use crate::types::{FastqPair, QualityVoteTotal};
+
+// which of two sequences seen equally often in a family --crm majority keeps
+#[derive(Clone, Copy, PartialEq, VariantArray)]
+pub(crate) enum MajorityTieBreak {
+    First,
+    Longest,
+    HighestQuality,
+}
+
+// one distinct pair of sequences seen in a family
+pub(crate) struct SequenceTally {
+    // the first pair seen with these sequences, which is the one written
+    pub(crate) pair: FastqPair,
+    pub(crate) count: usize,
+    // how many of the family's pairs came before that first one
+    pub(crate) first_seen: usize,
+    // summed Phred quality over every copy
+    quality_total: QualityVoteTotal,
+}
+
+impl SequenceTally {
+    fn length(&self) -> usize {
+        self.pair.0.seq().len() + self.pair.1.seq().len()
+    }
+}
+
+// every distinct (forward, reverse) sequence pair in a family, and how often each was seen. forward reads are compared
+// from after their cell barcode and UMI, which can differ within a bin
+#[derive(Default)]
+pub(crate) struct SequenceCounts {
+    pub(crate) size: usize,
+    tallies: HashMap<(Vec<u8>, Vec<u8>), SequenceTally>,
+}
+
+impl SequenceCounts {
+    pub(crate) fn add(&mut self, pair: &FastqPair, prefix_length: usize, phred_correction: u8) {
+        let quality = pair.0.qual().iter().chain(pair.1.qual())
+            .map(|quality| (quality - phred_correction) as QualityVoteTotal)
+            .sum::<QualityVoteTotal>();
+        let sequences = (pair.0.seq()[prefix_length..].to_vec(), pair.1.seq().to_vec());
+
+        let tally = self.tallies.entry(sequences).or_insert_with(|| SequenceTally {
+            pair: pair.clone(),
+            count: 0,
+            first_seen: self.size,
+            quality_total: 0,
+        });
+        tally.count += 1;
+        tally.quality_total += quality;
+        self.size += 1;
+    }
+
+    // the most often seen sequences, ties broken as asked and then by which came first
+    pub(crate) fn majority(&self, tie_break: MajorityTieBreak) -> &SequenceTally {
+        self.tallies.values()
+            .max_by(|a, b| a.count.cmp(&b.count)
+                .then_with(|| match tie_break {
+                    MajorityTieBreak::First => Ordering::Equal,
+                    MajorityTieBreak::Longest => a.length().cmp(&b.length()),
+                    // mean quality over every copy, compared without dividing so equal means really are ties
+                    MajorityTieBreak::HighestQuality => (a.quality_total * b.length() as QualityVoteTotal)
+                        .cmp(&(b.quality_total * a.length() as QualityVoteTotal)),
+                })
+                .then_with(|| b.first_seen.cmp(&a.first_seen)))
+            .unwrap()
+    }
+}
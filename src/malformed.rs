@@ -0,0 +1,90 @@
+use std::fmt::{Display, Formatter};
+
+use bio::io::fastq;
+
+// what can be wrong with a record that still parsed
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum RecordFault {
+    MissingId,
+    LengthMismatch,
+    // anything but A, C, G, T or N (in either case)
+    UnexpectedBase(u8),
+    // below the Phred offset, or not printable
+    QualityOutOfRange(u8),
+}
+
+impl Display for RecordFault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordFault::MissingId => write!(f, "it has no id"),
+            RecordFault::LengthMismatch => write!(f, "its sequence and qualities are different lengths"),
+            RecordFault::UnexpectedBase(base) => {
+                write!(f, "its sequence has {} where only A, C, G, T and N are allowed", base.escape_ascii())
+            }
+            RecordFault::QualityOutOfRange(quality) => {
+                write!(f, "its qualities have {}, outside the Phred range for this encoding (is it --phred64?)",
+                       quality.escape_ascii())
+            }
+        }
+    }
+}
+
+// the first thing (if anything) that would trip up processing further on
+pub(crate) fn find_fault(record: &fastq::Record, phred_correction: u8) -> Option<RecordFault> {
+    if record.id().is_empty() {
+        return Some(RecordFault::MissingId);
+    }
+    if record.seq().len() != record.qual().len() {
+        return Some(RecordFault::LengthMismatch);
+    }
+    if let Some(base) = record.seq().iter().find(|base| !b"ACGTNacgtn".contains(base)) {
+        return Some(RecordFault::UnexpectedBase(*base));
+    }
+    record.qual().iter()
+        .find(|quality| !(phred_correction..=b'~').contains(quality))
+        .map(|quality| RecordFault::QualityOutOfRange(*quality))
+}
+
+// the record with every base but A, C, G and T (in either case) read as N
+pub(crate) fn mask_unexpected_bases(record: &fastq::Record) -> fastq::Record {
+    let seq = record.seq().iter()
+        .map(|base| match b"ACGTNacgtn".contains(base) {
+            true => *base,
+            false => b'N',
+        })
+        .collect::<Vec<_>>();
+    fastq::Record::with_attrs(record.id(), record.desc(), &seq, record.qual())
+}
+
+#[derive(Default)]
+pub(crate) struct RecordFaultCount {
+    pub(crate) missing_id: usize,
+    pub(crate) length_mismatch: usize,
+    pub(crate) unexpected_base: usize,
+    pub(crate) quality_out_of_range: usize,
+}
+
+impl RecordFaultCount {
+    pub(crate) fn add(&mut self, fault: RecordFault) {
+        match fault {
+            RecordFault::MissingId => self.missing_id += 1,
+            RecordFault::LengthMismatch => self.length_mismatch += 1,
+            RecordFault::UnexpectedBase(_) => self.unexpected_base += 1,
+            RecordFault::QualityOutOfRange(_) => self.quality_out_of_range += 1,
+        }
+    }
+
+    pub(crate) fn total(&self) -> usize {
+        self.missing_id + self.length_mismatch + self.unexpected_base + self.quality_out_of_range
+    }
+}
+
+impl Display for RecordFaultCount {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no id: {}\n\
+        sequence and qualities of different lengths: {}\n\
+        base other than A, C, G, T or N: {}\n\
+        quality outside the Phred range: {}",
+               self.missing_id, self.length_mismatch, self.unexpected_base, self.quality_out_of_range)
+    }
+}
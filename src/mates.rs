@@ -0,0 +1,322 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+
+use bio::io::fastq;
+
+use crate::error::GrebeError;
+use crate::header::ReadHeader;
+use crate::malformed::{find_fault, mask_unexpected_bases, RecordFault, RecordFaultCount};
+use crate::reader::read_error;
+use crate::types::{FastqPair, WhichRead};
+
+pub(crate) enum Mates {
+    Pair(FastqPair),
+    // when repairing, a record whose mate never turned up (or not before it fell out of the buffer); when lenient, a
+    // record whose mate was malformed
+    Orphan(fastq::Record, WhichRead),
+    // when lenient, a record that would otherwise have stopped the run
+    Malformed(fastq::Record, WhichRead, RecordFault),
+}
+
+// records from one file waiting for their mate, oldest first
+#[derive(Default)]
+struct MateBuffer {
+    // each record with when it was inserted, so a stale entry in `order` can be told from a live one
+    records: HashMap<String, (usize, fastq::Record)>,
+    // may still hold names since matched or replaced by a duplicate, which are skipped over; compacted whenever these
+    // outnumber the live ones, so it stays within twice the buffer
+    order: VecDeque<(usize, String)>,
+    inserted: usize,
+}
+
+impl MateBuffer {
+    fn insert(&mut self, name: String, record: fastq::Record) -> Option<fastq::Record> {
+        self.inserted += 1;
+        self.order.push_back((self.inserted, name.clone()));
+        let duplicate = self.records.insert(name, (self.inserted, record)).map(|(_, record)| record);
+        self.compact();
+        duplicate
+    }
+
+    fn remove(&mut self, name: &str) -> Option<fastq::Record> {
+        let (_, record) = self.records.remove(name)?;
+        self.compact();
+        Some(record)
+    }
+
+    fn compact(&mut self) {
+        if self.order.len() > 2 * self.records.len() {
+            let records = &self.records;
+            self.order.retain(|(inserted, name)| records.get(name).is_some_and(|(live, _)| live == inserted));
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<fastq::Record> {
+        while let Some((inserted, name)) = self.order.pop_front() {
+            if self.records.get(&name).is_some_and(|(live, _)| *live == inserted) {
+                return self.records.remove(&name).map(|(_, record)| record);
+            }
+        }
+        None
+    }
+}
+
+// reads both files in step. by default, each pair has to be mates (same name, ignoring /1 and /2 and descriptions);
+// with a repair buffer, records are instead matched up by name within that many unmatched records per file, as
+// BBTools' repair.sh does
+pub(crate) struct MatePairer<R: Iterator<Item = fastq::Result<fastq::Record>>> {
+    records: (R, R),
+    paths: (PathBuf, PathBuf),
+    record_counts: (usize, usize),
+    repair_buffer: Option<usize>,
+    phred_correction: u8,
+    // set aside malformed records rather than stopping at the first
+    lenient: bool,
+    // outside --lenient, records read with their unexpected bases as N
+    pub(crate) masked: RecordFaultCount,
+    waiting: (MateBuffer, MateBuffer),
+    // what's been worked out but not yet handed out
+    ready: VecDeque<Result<Mates, GrebeError>>,
+}
+
+impl<R: Iterator<Item = fastq::Result<fastq::Record>>> MatePairer<R> {
+    pub(crate) fn new(records: (R, R), paths: (PathBuf, PathBuf), repair_buffer: Option<usize>, phred_correction: u8,
+                      lenient: bool) -> Self {
+        MatePairer {
+            records,
+            paths,
+            record_counts: (0, 0),
+            repair_buffer,
+            phred_correction,
+            lenient,
+            masked: Default::default(),
+            waiting: Default::default(),
+            ready: Default::default(),
+        }
+    }
+
+    // None if the record was malformed and has been set aside
+    fn check(&mut self, maybe_record: fastq::Result<fastq::Record>, which_read: WhichRead, record_number: usize)
+             -> Result<Option<fastq::Record>, GrebeError> {
+        let path_buf = match which_read {
+            WhichRead::FORWARD => &self.paths.0,
+            WhichRead::REVERSE => &self.paths.1,
+        };
+        // a record that didn't even parse leaves the reader lost, so there's no going on from it
+        let record = maybe_record.map_err(|err| read_error(err, path_buf, record_number))?;
+        match find_fault(&record, self.phred_correction) {
+            None => Ok(Some(record)),
+            Some(fault) if self.lenient => {
+                self.ready.push_back(Ok(Mates::Malformed(record, which_read, fault)));
+                Ok(None)
+            }
+            // other IUPAC codes and the . of old Illumina output don't get in the way of anything; they just say as
+            // little about the base as N does. a later fault would still stop the run
+            Some(unexpected @ RecordFault::UnexpectedBase(_)) => {
+                let record = mask_unexpected_bases(&record);
+                match find_fault(&record, self.phred_correction) {
+                    None => {
+                        self.masked.add(unexpected);
+                        Ok(Some(record))
+                    }
+                    Some(fault) => Err(GrebeError::MalformedRecord(path_buf.clone(), record_number, fault.to_string())),
+                }
+            }
+            Some(fault) => Err(GrebeError::MalformedRecord(path_buf.clone(), record_number, fault.to_string())),
+        }
+    }
+
+    fn truncated(&self, which_read: WhichRead) -> GrebeError {
+        let (read, record_count) = match which_read {
+            WhichRead::FORWARD => ("forward", self.record_counts.0),
+            WhichRead::REVERSE => ("reverse", self.record_counts.1),
+        };
+        GrebeError::MatesOutOfSync(record_count + 1, format!("the {read} file ended before the other"))
+    }
+
+    fn next_strict(&mut self) -> Option<Result<Mates, GrebeError>> {
+        if let Some(mates) = self.ready.pop_front() {
+            return Some(mates);
+        }
+
+        let (forward, reverse) = match (self.records.0.next(), self.records.1.next()) {
+            (None, None) => return None,
+            (Some(_), None) => return Some(Err(self.truncated(WhichRead::REVERSE))),
+            (None, Some(_)) => return Some(Err(self.truncated(WhichRead::FORWARD))),
+            (Some(forward), Some(reverse)) => (forward, reverse),
+        };
+        self.record_counts = (self.record_counts.0 + 1, self.record_counts.1 + 1);
+        let record_number = self.record_counts.0;
+
+        let read_pair = match (self.check(forward, WhichRead::FORWARD, record_number),
+                               self.check(reverse, WhichRead::REVERSE, record_number)) {
+            (Ok(Some(forward)), Ok(Some(reverse))) => (forward, reverse),
+            (Err(err), _) | (_, Err(err)) => return Some(Err(err)),
+            // one or both were set aside; whatever's left has no mate
+            (Ok(forward), Ok(reverse)) => {
+                if let Some(forward) = forward {
+                    self.ready.push_back(Ok(Mates::Orphan(forward, WhichRead::FORWARD)));
+                }
+                if let Some(reverse) = reverse {
+                    self.ready.push_back(Ok(Mates::Orphan(reverse, WhichRead::REVERSE)));
+                }
+                return self.ready.pop_front();
+            }
+        };
+
+        let names = (ReadHeader::parse(&read_pair.0).name, ReadHeader::parse(&read_pair.1).name);
+        if names.0 != names.1 {
+            return Some(Err(GrebeError::MatesOutOfSync(record_number, format!(
+                "the forward file has {} but the reverse file has {}", names.0, names.1))));
+        }
+        Some(Ok(Mates::Pair(read_pair)))
+    }
+
+    // pair the record up with its mate if that's already been read, otherwise wait for it
+    fn place(&mut self, record: fastq::Record, which_read: WhichRead, repair_buffer: usize) {
+        let name = ReadHeader::parse(&record).name.to_owned();
+        let (own, other) = match which_read {
+            WhichRead::FORWARD => (&mut self.waiting.0, &mut self.waiting.1),
+            WhichRead::REVERSE => (&mut self.waiting.1, &mut self.waiting.0),
+        };
+
+        match other.remove(&name) {
+            Some(mate) => self.ready.push_back(Ok(Mates::Pair(match which_read {
+                WhichRead::FORWARD => (record, mate),
+                WhichRead::REVERSE => (mate, record),
+            }))),
+            None => {
+                // a name seen twice in one file can only pair once; the older record gives way
+                if let Some(duplicate) = own.insert(name, record) {
+                    self.ready.push_back(Ok(Mates::Orphan(duplicate, which_read)));
+                }
+                if own.records.len() > repair_buffer {
+                    if let Some(oldest) = own.pop_oldest() {
+                        self.ready.push_back(Ok(Mates::Orphan(oldest, which_read)));
+                    }
+                }
+            }
+        }
+    }
+
+    fn next_repair(&mut self, repair_buffer: usize) -> Option<Result<Mates, GrebeError>> {
+        loop {
+            if let Some(mates) = self.ready.pop_front() {
+                return Some(mates);
+            }
+
+            let (forward, reverse) = (self.records.0.next(), self.records.1.next());
+            if forward.is_none() && reverse.is_none() {
+                // nothing left to wait for
+                return match self.waiting.0.pop_oldest() {
+                    Some(record) => Some(Ok(Mates::Orphan(record, WhichRead::FORWARD))),
+                    None => self.waiting.1.pop_oldest().map(|record| Ok(Mates::Orphan(record, WhichRead::REVERSE))),
+                };
+            }
+
+            if let Some(forward) = forward {
+                self.record_counts.0 += 1;
+                match self.check(forward, WhichRead::FORWARD, self.record_counts.0) {
+                    Ok(Some(record)) => self.place(record, WhichRead::FORWARD, repair_buffer),
+                    Ok(None) => {}
+                    Err(err) => self.ready.push_back(Err(err)),
+                }
+            }
+            if let Some(reverse) = reverse {
+                self.record_counts.1 += 1;
+                match self.check(reverse, WhichRead::REVERSE, self.record_counts.1) {
+                    Ok(Some(record)) => self.place(record, WhichRead::REVERSE, repair_buffer),
+                    Ok(None) => {}
+                    Err(err) => self.ready.push_back(Err(err)),
+                }
+            }
+        }
+    }
+}
+
+impl<R: Iterator<Item = fastq::Result<fastq::Record>>> Iterator for MatePairer<R> {
+    type Item = Result<Mates, GrebeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.repair_buffer {
+            None => self.next_strict(),
+            Some(repair_buffer) => self.next_repair(repair_buffer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &str) -> fastq::Record {
+        fastq::Record::with_attrs(id, None, b"ACGT", b"IIII")
+    }
+
+    fn pairer(forward: &[&str], reverse: &[&str], repair_buffer: Option<usize>)
+              -> MatePairer<std::vec::IntoIter<fastq::Result<fastq::Record>>> {
+        let records = |ids: &[&str]| ids.iter().map(|id| Ok(record(id))).collect::<Vec<_>>().into_iter();
+        MatePairer::new((records(forward), records(reverse)), Default::default(), repair_buffer, 33, false)
+    }
+
+    // each pair's name, or the name and read of each orphan
+    fn outcomes(pairer: MatePairer<std::vec::IntoIter<fastq::Result<fastq::Record>>>) -> Vec<String> {
+        pairer
+            .map(|mates| match mates.ok().unwrap() {
+                Mates::Pair((forward, reverse)) => {
+                    assert_eq!(ReadHeader::parse(&forward).name, ReadHeader::parse(&reverse).name);
+                    ReadHeader::parse(&forward).name.to_owned()
+                }
+                Mates::Orphan(record, WhichRead::FORWARD) => format!("{} forward", record.id()),
+                Mates::Orphan(record, WhichRead::REVERSE) => format!("{} reverse", record.id()),
+                Mates::Malformed(..) => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn strict_reports_the_first_mismatched_pair() {
+        let mut pairer = pairer(&["a/1", "b/1", "c/1"], &["a/2", "c/2", "b/2"], None);
+        assert!(matches!(pairer.next(), Some(Ok(Mates::Pair(_)))));
+        assert!(matches!(pairer.next(), Some(Err(GrebeError::MatesOutOfSync(2, _)))));
+    }
+
+    #[test]
+    fn repair_pairs_mates_out_of_order() {
+        let pairer = pairer(&["a/1", "b/1", "c/1", "d/1"], &["c/2", "a/2", "d/2", "b/2"], Some(4));
+        assert_eq!(outcomes(pairer), ["a", "c", "d", "b"]);
+    }
+
+    #[test]
+    fn repair_gives_up_on_mates_further_apart_than_the_buffer() {
+        let pairer = pairer(&["a", "b", "c", "d"], &["d", "b", "c", "a"], Some(1));
+        assert_eq!(outcomes(pairer), ["a forward", "b", "c", "d", "a reverse"]);
+    }
+
+    #[test]
+    fn repair_orphans_the_older_of_two_records_with_one_name() {
+        let pairer = pairer(&["a", "a", "b"], &["b", "c", "a"], Some(4));
+        assert_eq!(outcomes(pairer), ["a forward", "b", "a", "c reverse"]);
+    }
+
+    #[test]
+    fn buffer_order_stays_bounded() {
+        let mut buffer = MateBuffer::default();
+        for round in 0..100 {
+            let name = format!("read{}", round % 3);
+            buffer.insert(name.clone(), record(&name));
+            if round % 2 == 0 {
+                buffer.remove(&name);
+            }
+            assert!(buffer.order.len() <= 2 * buffer.records.len().max(1));
+        }
+
+        // replaced and removed names left in the order are skipped over
+        let mut oldest = vec![];
+        while let Some(record) = buffer.pop_oldest() {
+            oldest.push(record.id().to_owned());
+        }
+        assert_eq!(oldest.len(), 2);
+        assert!(buffer.records.is_empty());
+    }
+}
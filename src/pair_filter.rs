@@ -0,0 +1,151 @@
+use std::cmp::min;
+use std::ops::Range;
+
+use bio::io::fastq;
+use bio::utils::TextSlice;
+
+use crate::header::ReadHeader;
+use crate::insert::{InsertClass, InsertFilter};
+use crate::pair_handling::PairDropReason;
+use crate::types::{FastqPair, UMIVec, WhichRead};
+use crate::util::check_primer;
+
+pub(crate) enum PairVerdict {
+    Keep,
+    // the other mate was fully masked; only this one survives
+    Unpaired(WhichRead),
+    Drop(PairDropReason),
+    Artifact(InsertClass),
+}
+
+// everything needed to decide whether a pair is worth UMI handling, without touching any output
+pub(crate) struct PairFilter<'a> {
+    // cell barcode at the very start of the forward read, before the UMI
+    pub(crate) barcode_length: usize,
+    // drop pairs either of whose headers says the cluster failed the instrument's filter
+    pub(crate) drop_filtered: bool,
+    pub(crate) umi_length: usize,
+    // how many of those bases are at the start of the reverse read instead (the second half of a duplex UMI, with
+    // --duplex-layout split); they come after the forward read's in the UMI
+    pub(crate) reverse_umi_length: usize,
+    // how far an indel in the UMI may move the forward primer from where the UMI length says it starts
+    pub(crate) umi_shift: usize,
+    pub(crate) enforce_primers: (Option<TextSlice<'a>>, Option<TextSlice<'a>>),
+    pub(crate) insert_filter: InsertFilter,
+    pub(crate) phred_correction: u8,
+    // UMI bases below this quality are masked to N and match anything
+    pub(crate) umi_min_quality: u8,
+    // applies to cell barcodes too
+    pub(crate) max_umi_wildcards: usize,
+}
+
+impl PairFilter<'_> {
+    // bases at the start of the forward read before the template: cell barcode, then UMI
+    pub(crate) fn prefix_length(&self) -> usize {
+        self.barcode_length + self.umi_length - self.reverse_umi_length
+    }
+
+    // part of a read, with any base too poor to trust turned into a wildcard (N)
+    fn masked(&self, record: &fastq::Record, range: Range<usize>) -> UMIVec {
+        record.seq()[range.clone()].iter()
+            .zip(&record.qual()[range])
+            .map(|(base, qual)| match qual.saturating_sub(self.phred_correction) < self.umi_min_quality {
+                true => b'N',
+                false => base.to_ascii_uppercase(),
+            })
+            .collect()
+    }
+
+    pub(crate) fn barcode(&self, forward: &fastq::Record) -> UMIVec {
+        self.masked(forward, 0..self.barcode_length)
+    }
+
+    pub(crate) fn umi(&self, read_pair: &FastqPair) -> UMIVec {
+        [self.masked(&read_pair.0, self.barcode_length..self.prefix_length()),
+            self.masked(&read_pair.1, 0..self.reverse_umi_length)].concat()
+    }
+
+    pub(crate) fn umi_qualities(&self, read_pair: &FastqPair) -> Vec<u8> {
+        [&read_pair.0.qual()[self.barcode_length..self.prefix_length()],
+            &read_pair.1.qual()[..self.reverse_umi_length]].concat()
+    }
+
+    pub(crate) fn screen(&self, read_pair: &FastqPair) -> PairVerdict {
+        if self.drop_filtered && [&read_pair.0, &read_pair.1].into_iter()
+            .any(|record| ReadHeader::parse(record).is_filtered == Some(true)) {
+            return PairVerdict::Drop(PairDropReason::FailedFilter);
+        }
+
+        let n_closure = |s: &u8| *s == b'N';
+        match (read_pair.0.seq().iter().all(n_closure), read_pair.1.seq().iter().all(n_closure)) {
+            (true, false) => return PairVerdict::Unpaired(WhichRead::REVERSE),
+            (false, true) => return PairVerdict::Unpaired(WhichRead::FORWARD),
+            (true, true) => return PairVerdict::Drop(PairDropReason::BothMasked),
+            _ => {}
+        }
+
+        if let Some(forward_primer) = self.enforce_primers.0 {
+            if read_pair.0.seq().len() < self.prefix_length() + forward_primer.len() {
+                return PairVerdict::Drop(PairDropReason::NoForwardPrimer);
+            }
+
+            let starts_with_primer = check_primer(forward_primer, read_pair.0.seq())
+                .unwrap_or_default();
+            let prefix_length = self.prefix_length();
+            let primer_starts = prefix_length.saturating_sub(self.umi_shift)..=prefix_length + self.umi_shift;
+            let starts_with_umi_then_primer = primer_starts
+                .filter(|primer_start| primer_start + forward_primer.len() <= read_pair.0.seq().len())
+                .any(|primer_start| check_primer(forward_primer, &read_pair.0.seq()[primer_start..])
+                    .unwrap_or_default());
+
+            if self.prefix_length() > 0 && starts_with_primer && !starts_with_umi_then_primer {
+                // very unlikely the UMI then following seq is the primer; we will call this a bad UMI addition
+                return PairVerdict::Drop(PairDropReason::UMIIsForwardPrimer);
+            } else if !starts_with_umi_then_primer {
+                // primer not present where it should be
+                return PairVerdict::Drop(PairDropReason::NoForwardPrimer);
+            }
+        }
+        if let Some(reverse_primer) = self.enforce_primers.1 {
+            if read_pair.1.seq().len() < self.reverse_umi_length + reverse_primer.len() {
+                return PairVerdict::Drop(PairDropReason::NoReversePrimer);
+            }
+
+            let starts_with_primer = check_primer(reverse_primer, &read_pair.1.seq()[self.reverse_umi_length..])
+                .unwrap_or_default();
+
+            if !starts_with_primer {
+                return PairVerdict::Drop(PairDropReason::NoReversePrimer);
+            }
+        }
+
+        if self.prefix_length() > 0 {
+            // a barcode or UMI cut short by the end of the read is nothing but wildcards past that point
+            if read_pair.0.seq().len() < self.prefix_length() || read_pair.1.seq().len() < self.reverse_umi_length {
+                return PairVerdict::Drop(match read_pair.0.seq().len() < self.barcode_length {
+                    true => PairDropReason::BarcodeWildcards,
+                    false => PairDropReason::UMIWildcards,
+                });
+            }
+
+            let wildcards = |segment: UMIVec| segment.iter().filter(|base| **base == b'N').count();
+            if wildcards(self.barcode(&read_pair.0)) > self.max_umi_wildcards {
+                return PairVerdict::Drop(PairDropReason::BarcodeWildcards);
+            }
+            if wildcards(self.umi(read_pair)) > self.max_umi_wildcards {
+                return PairVerdict::Drop(PairDropReason::UMIWildcards);
+            }
+        }
+
+        if self.insert_filter.is_active() {
+            let forward_insert = &read_pair.0.seq()[min(self.prefix_length(), read_pair.0.seq().len())..];
+            let reverse_insert = &read_pair.1.seq()[min(self.reverse_umi_length, read_pair.1.seq().len())..];
+            let insert_class = self.insert_filter.classify(forward_insert, reverse_insert);
+            if insert_class != InsertClass::Expected {
+                return PairVerdict::Artifact(insert_class);
+            }
+        }
+
+        PairVerdict::Keep
+    }
+}
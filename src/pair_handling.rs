@@ -1,16 +1,19 @@
-use std::{io, iter};
-use std::cmp::{max, Ordering};
-use std::collections::{HashMap, HashSet};
+use std::cmp::{max, min, Ordering};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{Display, Formatter};
-use std::io::BufWriter;
 
 use bio::bio_types::sequence::SequenceRead;
 use bio::io::fastq;
 use itertools::Itertools;
 use strum::VariantArray;
 
-use crate::types::{BaseQualityVotes, FastqPair, OutputWriters, QualityVoteTotal, QualityVoteVec, UMIVec, WhichRead};
-use crate::writer::WriterMaybeGzip;
+use crate::consensus::{
+    duplex_partner, ConsensusCaller, ConsensusCount, ConsensusPair, ConsensusRead, DuplexCount, FamilyVotes,
+};
+use crate::error::GrebeError;
+use crate::insert::InsertClass;
+use crate::majority::{MajorityTieBreak, SequenceCounts};
+use crate::types::{FastqPair, OutputWriters, BinKey, QualityVoteTotal, WhichRead};
 
 #[derive(Clone, Copy, PartialEq, VariantArray)]
 pub(crate) enum UMICollisionResolutionMethod {
@@ -20,60 +23,267 @@ pub(crate) enum UMICollisionResolutionMethod {
     KeepLongestLeft,
     KeepLongestRight,
     KeepLongestExtend,
+    KeepHighestQuality,
+    Majority,
     QualityVote,
 }
 
+// what a pair's (or a mate's) base qualities are boiled down to for --crm highest-quality
+#[derive(Clone, Copy, PartialEq, VariantArray)]
+pub(crate) enum QualityScore {
+    Mean,
+    Sum,
+}
+
+// which of two equally scored pairs --crm highest-quality keeps
+#[derive(Clone, Copy, PartialEq, VariantArray)]
+pub(crate) enum QualityTieBreak {
+    First,
+    Last,
+    Longest,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct QualityRanking {
+    pub(crate) score: QualityScore,
+    pub(crate) tie_break: QualityTieBreak,
+    pub(crate) phred_correction: u8,
+}
+
+impl Default for QualityRanking {
+    fn default() -> Self {
+        QualityRanking {
+            score: QualityScore::Mean,
+            tie_break: QualityTieBreak::First,
+            phred_correction: 33,
+        }
+    }
+}
+
+impl QualityRanking {
+    // summed Phred quality and length of some reads, taken together
+    fn total(&self, reads: &[&fastq::Record]) -> (QualityVoteTotal, QualityVoteTotal) {
+        reads.iter().fold((0, 0), |(quality, length), read| (
+            quality + read.qual().iter()
+                .map(|quality| (quality - self.phred_correction) as QualityVoteTotal)
+                .sum::<QualityVoteTotal>(),
+            length + read.qual().len() as QualityVoteTotal
+        ))
+    }
+
+    // whether reads found later (both mates, or just one) should replace the ones already kept
+    fn keeps_new(&self, old: &[&fastq::Record], new: &[&fastq::Record]) -> bool {
+        let (old_quality, old_length) = self.total(old);
+        let (new_quality, new_length) = self.total(new);
+        let by_score = match self.score {
+            // compare means without dividing, so equal means really are ties
+            QualityScore::Mean => (new_quality * old_length).cmp(&(old_quality * new_length)),
+            QualityScore::Sum => new_quality.cmp(&old_quality),
+        };
+        match by_score.then_with(|| match self.tie_break {
+            QualityTieBreak::Longest => new_length.cmp(&old_length),
+            _ => Ordering::Equal,
+        }) {
+            Ordering::Less => false,
+            Ordering::Equal => self.tie_break == QualityTieBreak::Last,
+            Ordering::Greater => true,
+        }
+    }
+}
+
+// what --crm keep-longest-extend does with two pairs (or reads) of the same length
+#[derive(Clone, Copy, PartialEq, VariantArray)]
+pub(crate) enum ExtendTieBreak {
+    First,
+    Last,
+    Quality,
+    Merge,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct Extension {
+    // how much two reads may disagree and still count as one extending the other: the summed Phred quality of the
+    // mismatched bases, each counted at the lower of the two reads' qualities there (but at least 1)
+    pub(crate) mismatch_budget: u32,
+    pub(crate) tie_break: ExtendTieBreak,
+    pub(crate) phred_correction: u8,
+}
+
+impl Default for Extension {
+    fn default() -> Self {
+        Extension {
+            mismatch_budget: 0,
+            tie_break: ExtendTieBreak::First,
+            phred_correction: 33,
+        }
+    }
+}
+
+impl Extension {
+    // whether two reads agree, within the mismatch budget, as far as both go
+    fn agrees(&self, old: &fastq::Record, new: &fastq::Record) -> bool {
+        old.seq().iter().zip(new.seq())
+            .zip(old.qual().iter().zip(new.qual()))
+            .filter(|((old_base, new_base), _)| old_base != new_base)
+            // even a Q0 mismatch costs something, so a budget of 0 still means exact agreement
+            .map(|(_, (old_quality, new_quality))| {
+                max((min(old_quality, new_quality) - self.phred_correction) as u32, 1)
+            })
+            .sum::<u32>() <= self.mismatch_budget
+    }
+
+    fn quality(&self, reads: &[&fastq::Record]) -> QualityVoteTotal {
+        reads.iter()
+            .flat_map(|read| read.qual())
+            .map(|quality| (quality - self.phred_correction) as QualityVoteTotal)
+            .sum()
+    }
+
+    // one read as long as the longer of two agreeing reads, taking the better quality base wherever both have one
+    fn merge(&self, old: &fastq::Record, new: &fastq::Record) -> fastq::Record {
+        let (longer, shorter) = match new.seq().len() > old.seq().len() {
+            true => (new, old),
+            false => (old, new),
+        };
+        let (seq, qual) = longer.seq().iter().zip(longer.qual()).enumerate()
+            .map(|(position, (&base, &quality))| match (shorter.seq().get(position), shorter.qual().get(position)) {
+                (Some(&other_base), Some(&other_quality)) if other_quality > quality => (other_base, other_quality),
+                _ => (base, quality),
+            })
+            .unzip::<u8, u8, Vec<u8>, Vec<u8>>();
+        fastq::Record::with_attrs(old.id(), old.desc(), &seq, &qual)
+    }
+}
+
 impl UMICollisionResolutionMethod {
-    fn _compare_for_extension(&self, old: &fastq::Record, new: &fastq::Record) -> fastq::Record {
+    // whether reads found later (both mates together, or just one) should replace the ones already kept
+    fn keeps_new(&self, quality_ranking: &QualityRanking, extension: &Extension, old: &[&fastq::Record],
+                 new: &[&fastq::Record]) -> bool {
+        let length = |reads: &[&fastq::Record]| reads.iter().map(|read| read.seq().len()).sum::<usize>();
         match self {
             UMICollisionResolutionMethod::KeepLongestLeft | UMICollisionResolutionMethod::KeepLongestRight |
             UMICollisionResolutionMethod::KeepLongestExtend => {
-                match old.seq().len().cmp(&new.seq().len()) {
+                match length(old).cmp(&length(new)) {
                     Ordering::Less => match self {
-                        // if keeping longest with extension, need to check content
-                        UMICollisionResolutionMethod::KeepLongestExtend => {
-                            if new.seq().starts_with(old.seq()) { (*new).clone() } else { (*old).clone() }
-                        }
+                        // if keeping longest with extension, need to check content: each read has to agree with the
+                        // one it replaces as far as both go
+                        UMICollisionResolutionMethod::KeepLongestExtend => old.iter().zip(new)
+                            .all(|(old, new)| extension.agrees(old, new)),
                         // if simply keeping longest, content does not matter
-                        _ => (*new).clone()
+                        _ => true
                     },
                     Ordering::Equal => match &self {
-                        // maybe allow user to choose here for KeepLongestExtend
-                        UMICollisionResolutionMethod::KeepLongestLeft |
-                        UMICollisionResolutionMethod::KeepLongestExtend => (*old).clone(),
-                        UMICollisionResolutionMethod::KeepLongestRight => (*new).clone(),
+                        UMICollisionResolutionMethod::KeepLongestLeft => false,
+                        UMICollisionResolutionMethod::KeepLongestRight => true,
+                        // a merge keeps what's already there, added to
+                        UMICollisionResolutionMethod::KeepLongestExtend => match extension.tie_break {
+                            ExtendTieBreak::First | ExtendTieBreak::Merge => false,
+                            ExtendTieBreak::Last => true,
+                            ExtendTieBreak::Quality => extension.quality(new) > extension.quality(old),
+                        },
                         _ => unreachable!()
                     },
-                    Ordering::Greater => (*old).clone()
+                    Ordering::Greater => false
                 }
             }
+            UMICollisionResolutionMethod::KeepHighestQuality => quality_ranking.keeps_new(old, new),
             _ => unimplemented!()
         }
     }
+
+    // a tie that --crm keep-longest-extend should settle by merging the reads
+    fn merges(&self, extension: &Extension, old: &[&fastq::Record], new: &[&fastq::Record]) -> bool {
+        let length = |reads: &[&fastq::Record]| reads.iter().map(|read| read.seq().len()).sum::<usize>();
+        *self == UMICollisionResolutionMethod::KeepLongestExtend && extension.tie_break == ExtendTieBreak::Merge
+            && length(old) == length(new) && old.iter().zip(new).all(|(old, new)| extension.agrees(old, new))
+    }
+
+    // the pair to keep out of the one already kept and one found later, and whether the two mates on their own would
+    // have chosen differently. unless `per_mate`, pairs are only ever kept (or merged) whole
+    fn choose(&self, quality_ranking: &QualityRanking, extension: &Extension, per_mate: bool, old: &FastqPair,
+              new: &FastqPair) -> (FastqPair, bool) {
+        let forward = self.keeps_new(quality_ranking, extension, &[&old.0], &[&new.0]);
+        let reverse = self.keeps_new(quality_ranking, extension, &[&old.1], &[&new.1]);
+        let pick = |keeps_new: bool, old: &fastq::Record, new: &fastq::Record| {
+            match (self.merges(extension, &[old], &[new]), keeps_new) {
+                (true, _) => extension.merge(old, new),
+                (false, true) => new.clone(),
+                (false, false) => old.clone(),
+            }
+        };
+
+        let kept = match per_mate {
+            true => (pick(forward, &old.0, &new.0), pick(reverse, &old.1, &new.1)),
+            false if self.merges(extension, &[&old.0, &old.1], &[&new.0, &new.1]) => {
+                (extension.merge(&old.0, &new.0), extension.merge(&old.1, &new.1))
+            }
+            false => match self.keeps_new(quality_ranking, extension, &[&old.0, &old.1], &[&new.0, &new.1]) {
+                true => new.clone(),
+                false => old.clone(),
+            },
+        };
+        (kept, forward != reverse)
+    }
 }
 
 
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum PairDropReason {
+    BothMasked,
+    UMIIsForwardPrimer,
+    NoForwardPrimer,
+    NoReversePrimer,
+    RareUMI,
+    UMIWildcards,
+    NotWhitelisted,
+    AmbiguousWhitelistMatch,
+    BarcodeWildcards,
+    BarcodeNotWhitelisted,
+    AmbiguousBarcodeMatch,
+    FailedFilter,
+}
+
+#[derive(Default)]
 pub(crate) struct PairDropReasonCount {
     pub(crate) both_masked: usize,
     pub(crate) umi_is_forward_primer: usize,
     pub(crate) no_forward_primer: usize,
     pub(crate) no_reverse_primer: usize,
+    pub(crate) primer_dimer: usize,
+    pub(crate) off_target: usize,
+    pub(crate) rare_umi: usize,
+    pub(crate) umi_wildcards: usize,
+    pub(crate) not_whitelisted: usize,
+    pub(crate) ambiguous_whitelist_match: usize,
+    pub(crate) barcode_wildcards: usize,
+    pub(crate) barcode_not_whitelisted: usize,
+    pub(crate) ambiguous_barcode_match: usize,
+    pub(crate) failed_filter: usize,
 }
 
 impl PairDropReasonCount {
-    pub(crate) fn total(&self) -> usize {
-        self.both_masked + self.umi_is_forward_primer + self.no_forward_primer + self.no_reverse_primer
+    pub(crate) fn add(&mut self, reason: PairDropReason) {
+        match reason {
+            PairDropReason::BothMasked => self.both_masked += 1,
+            PairDropReason::UMIIsForwardPrimer => self.umi_is_forward_primer += 1,
+            PairDropReason::NoForwardPrimer => self.no_forward_primer += 1,
+            PairDropReason::NoReversePrimer => self.no_reverse_primer += 1,
+            PairDropReason::RareUMI => self.rare_umi += 1,
+            PairDropReason::UMIWildcards => self.umi_wildcards += 1,
+            PairDropReason::NotWhitelisted => self.not_whitelisted += 1,
+            PairDropReason::AmbiguousWhitelistMatch => self.ambiguous_whitelist_match += 1,
+            PairDropReason::BarcodeWildcards => self.barcode_wildcards += 1,
+            PairDropReason::BarcodeNotWhitelisted => self.barcode_not_whitelisted += 1,
+            PairDropReason::AmbiguousBarcodeMatch => self.ambiguous_barcode_match += 1,
+            PairDropReason::FailedFilter => self.failed_filter += 1,
+        }
     }
-}
 
-impl Default for PairDropReasonCount {
-    fn default() -> Self {
-        PairDropReasonCount {
-            both_masked: 0,
-            umi_is_forward_primer: 0,
-            no_forward_primer: 0,
-            no_reverse_primer: 0,
-        }
+    pub(crate) fn total(&self) -> usize {
+        self.both_masked + self.umi_is_forward_primer + self.no_forward_primer + self.no_reverse_primer +
+            self.primer_dimer + self.off_target + self.rare_umi + self.umi_wildcards + self.not_whitelisted +
+            self.ambiguous_whitelist_match + self.barcode_wildcards + self.barcode_not_whitelisted +
+            self.ambiguous_barcode_match + self.failed_filter
     }
 }
 
@@ -82,40 +292,74 @@ impl Display for PairDropReasonCount {
         write!(f, "masked on both ends: {}\n\
         nonzero UMI length, forward primer specified, and forward read with UMI began with primer: {}\n\
         forward primer specified and not present: {}\n\
-        reverse primer specified and not present: {}",
-               self.both_masked, self.umi_is_forward_primer, self.no_forward_primer, self.no_reverse_primer)
+        reverse primer specified and not present: {}\n\
+        insert mostly primer sequence (primer dimer): {}\n\
+        insert length outside expected range (off-target product): {}\n\
+        UMI too rare to keep after clustering: {}\n\
+        too many N or low-quality bases in UMI: {}\n\
+        UMI not within radius of any whitelisted UMI: {}\n\
+        UMI equally close to more than one whitelisted UMI: {}\n\
+        too many N or low-quality bases in cell barcode: {}\n\
+        cell barcode not within radius of any whitelisted barcode: {}\n\
+        cell barcode equally close to more than one whitelisted barcode: {}\n\
+        flagged as failing the instrument's filter (Y in the header): {}",
+               self.both_masked, self.umi_is_forward_primer, self.no_forward_primer, self.no_reverse_primer,
+               self.primer_dimer, self.off_target, self.rare_umi, self.umi_wildcards, self.not_whitelisted,
+               self.ambiguous_whitelist_match, self.barcode_wildcards, self.barcode_not_whitelisted,
+               self.ambiguous_barcode_match, self.failed_filter)
     }
 }
 
 pub(crate) struct PairHandler {
     pub(crate) record_writers: OutputWriters,
     pub(crate) collision_resolution_method: UMICollisionResolutionMethod,
-    pub(crate) umi_bins: HashMap<UMIVec, HashSet<FastqPair>>,
+    // how --crm highest-quality ranks pairs
+    pub(crate) quality_ranking: QualityRanking,
+    // how --crm keep-longest-extend tolerates mismatches and settles ties
+    pub(crate) extension: Extension,
+    pub(crate) majority_tie_break: MajorityTieBreak,
+    // with a keep-longest method or --crm highest-quality, choose each mate on its own rather than keeping whole pairs
+    pub(crate) per_mate: bool,
+    // collisions where the forward and reverse reads on their own would have kept different pairs
+    pub(crate) mate_disagreements: usize,
+    pub(crate) umi_bins: HashMap<BinKey, HashSet<FastqPair>>,
+    // add the cell barcode and UMI of each pair's bin to its description as SAM-style CB and UB tags
+    pub(crate) tag_headers: bool,
     pub(crate) phred_correction: u8,
     pub(crate) records_total: usize,
     pub(crate) records_good: usize,
     pub(crate) records_written: usize,
     pub(crate) records_unpaired: (usize, usize),
     pub(crate) pair_drop_reason_count: PairDropReasonCount,
-    // ATCG order, only populated if --crm quality-vote
-    pub(crate) quality_votes: HashMap<UMIVec, (QualityVoteVec, QualityVoteVec)>,
+    // only populated if --crm quality-vote
+    pub(crate) quality_votes: HashMap<BinKey, FamilyVotes>,
+    pub(crate) consensus_caller: ConsensusCaller,
+    pub(crate) consensus_count: ConsensusCount,
+    // only populated if --crm majority
+    pub(crate) sequence_counts: HashMap<BinKey, SequenceCounts>,
+    // combine the consensuses of families whose UMIs are each other's halves swapped (both strands of a molecule)
+    pub(crate) duplex: bool,
+    pub(crate) duplex_count: DuplexCount,
+    // UMI bases at the start of the reverse read rather than the forward (see `PairFilter`)
+    pub(crate) reverse_umi_length: usize,
+    // write every pair, tagged with its family and whether it is the one --crm would have kept, instead of collapsing
+    pub(crate) mark_duplicates: bool,
+    // only populated if --mark-duplicates; every pair of each family, in the order they arrived
+    pub(crate) families: HashMap<BinKey, Vec<FastqPair>>,
 }
 
 impl Default for PairHandler {
     fn default() -> Self {
         PairHandler {
-            record_writers: OutputWriters {
-                paired: (
-                    fastq::Writer::from_bufwriter(BufWriter::new(WriterMaybeGzip::NULL(io::sink()))),
-                    fastq::Writer::from_bufwriter(BufWriter::new(WriterMaybeGzip::NULL(io::sink())))
-                ),
-                unpaired: (
-                    fastq::Writer::from_bufwriter(BufWriter::new(WriterMaybeGzip::NULL(io::sink()))),
-                    fastq::Writer::from_bufwriter(BufWriter::new(WriterMaybeGzip::NULL(io::sink())))
-                ),
-            },
+            record_writers: Default::default(),
             collision_resolution_method: UMICollisionResolutionMethod::KeepFirst,
+            quality_ranking: Default::default(),
+            extension: Default::default(),
+            majority_tie_break: MajorityTieBreak::First,
+            per_mate: false,
+            mate_disagreements: 0,
             umi_bins: Default::default(),
+            tag_headers: false,
             phred_correction: 33,
             records_total: 0,
             records_good: 0,
@@ -125,12 +369,20 @@ impl Default for PairHandler {
                 ..Default::default()
             },
             quality_votes: Default::default(),
+            consensus_caller: Default::default(),
+            consensus_count: Default::default(),
+            sequence_counts: Default::default(),
+            duplex: false,
+            duplex_count: Default::default(),
+            reverse_umi_length: 0,
+            mark_duplicates: false,
+            families: Default::default(),
         }
     }
 }
 
 impl PairHandler {
-    pub(crate) unsafe fn write_pair(&mut self, pair: FastqPair) {
+    pub(crate) unsafe fn write_pair(&mut self, pair: FastqPair) -> Result<(), GrebeError> {
         // TODO: reimplement slicing etc; increment some kind of dropped record counter
         self.records_written += 1;
 
@@ -141,20 +393,40 @@ impl PairHandler {
 
         self.record_writers.paired.0.write(
             std::str::from_utf8_unchecked(pair.0.name()),
-            Option::from(pair.0.id()),
+            pair.0.desc(),
             pair.0.seq(),
             pair.0.qual(),
-        )
-            .expect("couldn't write out a forward record");
-        self.record_writers.paired.0.write(
+        )?;
+        self.record_writers.paired.1.write(
             std::str::from_utf8_unchecked(pair.1.name()),
-            Option::from(pair.1.id()),
+            pair.1.desc(),
             pair.1.seq(),
             pair.1.qual(),
-        ).expect("couldn't write out a reverse record");
+        )
+    }
+
+    // how a bin is named in read names: its UMI, after its cell barcode if there is one
+    fn bin_label(key: &BinKey) -> String {
+        [&key.0, &key.1].into_iter()
+            .filter(|part| !part.is_empty())
+            .map(|part| String::from_utf8_lossy(part))
+            .join("_")
+    }
+
+    fn tag_description(key: &BinKey, desc: Option<&str>) -> String {
+        let tags = [("CB", &key.0), ("UB", &key.1)].into_iter()
+            .filter(|(_, part)| !part.is_empty())
+            .map(|(tag, part)| format!("{tag}:Z:{}", String::from_utf8_lossy(part)));
+        desc.into_iter().map(str::to_owned).chain(tags).join(" ")
+    }
+
+    fn tag_pair(key: &BinKey, pair: &FastqPair) -> FastqPair {
+        let tag = |record: &fastq::Record| fastq::Record::with_attrs(
+            record.id(), Some(&Self::tag_description(key, record.desc())), record.seq(), record.qual());
+        (tag(&pair.0), tag(&pair.1))
     }
 
-    pub(crate) fn write_unpaired(&mut self, record: fastq::Record, which_read: WhichRead) {
+    pub(crate) fn write_unpaired(&mut self, record: fastq::Record, which_read: WhichRead) -> Result<(), GrebeError> {
         match which_read {
             WhichRead::FORWARD => {
                 self.records_unpaired.0 += 1;
@@ -164,7 +436,7 @@ impl PairHandler {
                         Option::from(record.id()),
                         record.seq(),
                         record.qual(),
-                    ).expect("couldn't write out an unpaired forward record")
+                    )
                 }
             }
             WhichRead::REVERSE => {
@@ -175,41 +447,84 @@ impl PairHandler {
                         Option::from(record.id()),
                         record.seq(),
                         record.qual(),
-                    ).expect("couldn't write out an unpaired reverse record")
+                    )
                 }
             }
+        }
+    }
+
+    pub(crate) fn write_artifact(&mut self, pair: &FastqPair, insert_class: InsertClass) -> Result<(), GrebeError> {
+        match insert_class {
+            InsertClass::Expected => unreachable!(),
+            InsertClass::PrimerDimer => self.pair_drop_reason_count.primer_dimer += 1,
+            InsertClass::OffTarget => self.pair_drop_reason_count.off_target += 1,
+        }
+
+        // tag each mate so the side output can be tallied by product type
+        let tag = |record: &fastq::Record| match record.desc() {
+            Some(desc) => format!("{desc} grebe_artifact={insert_class}"),
+            None => format!("grebe_artifact={insert_class}"),
         };
+
+        self.record_writers.artifact.0.write(pair.0.id(), Some(&tag(&pair.0)), pair.0.seq(), pair.0.qual())?;
+        self.record_writers.artifact.1.write(pair.1.id(), Some(&tag(&pair.1)), pair.1.seq(), pair.1.qual())
     }
 
-    pub(crate) fn insert_pair(&mut self, umi: &UMIVec, pair: &FastqPair) {
+    pub(crate) fn insert_pair(&mut self, key: &BinKey, pair: &FastqPair) -> Result<(), GrebeError> {
+        if self.mark_duplicates {
+            // nothing can be written until the family is complete; the empty set marks the UMI as seen for binning
+            self.records_good += 1;
+            self.umi_bins.entry(key.clone()).or_default();
+            self.families.entry(key.clone()).or_default().push(pair.clone());
+            return Ok(());
+        }
+
+        // pairs held until the end are tagged as they are written, since their bin can still be moved (see `rebin`)
+        let tagged;
+        let written_now = match self.collision_resolution_method {
+            UMICollisionResolutionMethod::None => true,
+            UMICollisionResolutionMethod::KeepFirst => !key.1.contains(&b'N'),
+            _ => false,
+        };
+        let pair = match self.tag_headers && written_now {
+            true => {
+                tagged = Self::tag_pair(key, pair);
+                &tagged
+            }
+            false => pair,
+        };
+        // bases of the forward read taken up by the barcode and UMI
+        let prefix_length = key.0.len() + key.1.len() - self.reverse_umi_length;
+
         match self.collision_resolution_method {
             // special case: no comparison, etc., just go straight to disk
             UMICollisionResolutionMethod::None => unsafe {
                 self.records_good += 1;
 
-                let umi_space = [String::from_utf8_unchecked(umi.clone()), " ".parse().unwrap()].concat();
+                let label = Self::bin_label(key);
+                let label_space = [label.as_str(), " "].concat();
                 // write the record, add UMI
-                let id_prefix = match umi.len() {
+                let id_prefix = match label.len() {
                     0 => "",
-                    _ => &umi_space
+                    _ => &label_space
                 };
                 let pair_new = (
                     fastq::Record::with_attrs(
-                        &*(id_prefix.to_owned() + std::str::from_utf8_unchecked(pair.0.name())),
+                        &(id_prefix.to_owned() + std::str::from_utf8_unchecked(pair.0.name())),
                         pair.0.desc(),
-                        &*pair.0.seq(),
-                        &*pair.0.qual(),
+                        pair.0.seq(),
+                        pair.0.qual(),
                     ),
                     fastq::Record::with_attrs(
-                        &*(id_prefix.to_owned() + std::str::from_utf8_unchecked(pair.1.name())),
+                        &(id_prefix.to_owned() + std::str::from_utf8_unchecked(pair.1.name())),
                         pair.1.desc(),
-                        &*pair.1.seq(),
-                        &*pair.1.qual(),
+                        pair.1.seq(),
+                        pair.1.qual(),
                     )
                 );
-                self.write_pair(pair_new);
+                self.write_pair(pair_new)?;
             }
-            _ if !self.umi_bins.contains_key(umi) => {
+            _ if !self.umi_bins.contains_key(key) => {
                 let mut set = HashSet::<FastqPair>::new();
                 match self.collision_resolution_method {
                     UMICollisionResolutionMethod::None => {
@@ -219,18 +534,24 @@ impl PairHandler {
                     // special cases: `umi_bins` is involved but only to indicate a UMI has been seen
                     UMICollisionResolutionMethod::QualityVote => {
                         // create the "ballots" and save to disk later
-                        let mut votes = (
-                            Vec::<BaseQualityVotes>::new(), Vec::<BaseQualityVotes>::new()
-                        );
-                        votes.0.extend(iter::repeat((0, 0, 0, 0)).take(pair.0.len() - umi.len()));
-                        votes.1.extend(iter::repeat((0, 0, 0, 0)).take(pair.1.len()));
-
-                        Self::update_vote_vec(self.phred_correction, &mut votes, pair, umi.len());
-                        self.quality_votes.insert(umi.clone(), votes);
+                        let mut family = FamilyVotes::default();
+                        Self::update_vote_vec(&self.consensus_caller, self.phred_correction, &mut family, pair,
+                                              (prefix_length, self.reverse_umi_length));
+                        self.quality_votes.insert(key.clone(), family);
+                    }
+                    UMICollisionResolutionMethod::Majority => {
+                        // count this pair's sequences and pick the most common later
+                        let mut family = SequenceCounts::default();
+                        family.add(pair, prefix_length, self.phred_correction);
+                        self.sequence_counts.insert(key.clone(), family);
+                    }
+                    UMICollisionResolutionMethod::KeepFirst if key.1.contains(&b'N') => {
+                        // hold on to it, as the bin may yet be moved to a plain UMI
+                        set.insert(pair.clone());
                     }
                     UMICollisionResolutionMethod::KeepFirst => unsafe {
                         // write the record immediately; save memory
-                        self.write_pair(pair.clone());
+                        self.write_pair(pair.clone())?;
                         // save an empty set so we don't come here again
                     }
                     // un-special cases: full comparison with the contents of `umi_bins` is necessary
@@ -242,11 +563,11 @@ impl PairHandler {
                 // count the good record once; for all these cases it could be overridden, but the +1 will not change
                 self.records_good += 1;
                 // whatever we decided to put in the set (if anything), save it
-                self.umi_bins.insert(umi.clone(), set);
+                self.umi_bins.insert(key.clone(), set);
             }
             // guard for readability only
-            _ if self.umi_bins.contains_key(umi) => {
-                let set = self.umi_bins.get_mut(umi).unwrap();
+            _ if self.umi_bins.contains_key(key) => {
+                let set = self.umi_bins.get_mut(key).unwrap();
 
                 match self.collision_resolution_method {
                     UMICollisionResolutionMethod::None | UMICollisionResolutionMethod::KeepFirst => {
@@ -255,16 +576,13 @@ impl PairHandler {
                     // need to do a bit
                     UMICollisionResolutionMethod::QualityVote => {
                         // update the "ballots"
-                        let mut votes = self.quality_votes.get_mut(umi).unwrap();
-                        // stretch to size sufficient to fit data
-                        votes.0.extend(
-                            iter::repeat((0, 0, 0, 0))
-                                .take(max((pair.0.seq().len() - umi.len()).saturating_sub(votes.0.len()), 0)));
-                        votes.1.extend(
-                            iter::repeat((0, 0, 0, 0))
-                                .take(max(pair.1.seq().len().saturating_sub(votes.1.len()), 0)));
-
-                        Self::update_vote_vec(self.phred_correction, &mut votes, pair, umi.len());
+                        let family = self.quality_votes.get_mut(key).unwrap();
+                        Self::update_vote_vec(&self.consensus_caller, self.phred_correction, family, pair,
+                                              (prefix_length, self.reverse_umi_length));
+                    }
+                    UMICollisionResolutionMethod::Majority => {
+                        let family = self.sequence_counts.get_mut(key).unwrap();
+                        family.add(pair, prefix_length, self.phred_correction);
                     }
                     // un-special cases, again
                     UMICollisionResolutionMethod::KeepLast => {
@@ -273,129 +591,245 @@ impl PairHandler {
                         set.insert(pair.clone());
                     }
                     UMICollisionResolutionMethod::KeepLongestLeft | UMICollisionResolutionMethod::KeepLongestRight |
-                    UMICollisionResolutionMethod::KeepLongestExtend => {
+                    UMICollisionResolutionMethod::KeepLongestExtend |
+                    UMICollisionResolutionMethod::KeepHighestQuality => {
                         // this clone isn't the best but meh
                         let old = set.iter().exactly_one().unwrap().clone();
                         set.remove(&old);
 
-                        set.insert((
-                            self.collision_resolution_method._compare_for_extension(&old.0, &pair.0),
-                            self.collision_resolution_method._compare_for_extension(&old.1, &pair.1)
-                        ));
+                        let (kept, mates_disagree) = self.collision_resolution_method.choose(
+                            &self.quality_ranking, &self.extension, self.per_mate, &old, pair);
+                        if mates_disagree {
+                            self.mate_disagreements += 1;
+                        }
+                        set.insert(kept);
                     }
                 }
             }
             _ => unreachable!()
         }
+        Ok(())
     }
 
-    fn update_vote_vec(phred_correction: u8, votes: &mut (QualityVoteVec, QualityVoteVec), pair: &FastqPair, umi_len: usize) {
-        for (ind, (base, qual)) in pair.0.seq().iter()
-            .zip(pair.0.qual()).dropping(umi_len)
-            .enumerate() {
-            let vec_to_update = votes.0.get_mut(ind).unwrap();
-            let actual_qual = (qual - phred_correction) as QualityVoteTotal;
-
-            match base.to_ascii_uppercase() {
-                b'A' => vec_to_update.0 = vec_to_update.0.saturating_add(actual_qual),
-                b'T' => vec_to_update.1 = vec_to_update.1.saturating_add(actual_qual),
-                b'C' => vec_to_update.2 = vec_to_update.2.saturating_add(actual_qual),
-                b'G' => vec_to_update.3 = vec_to_update.3.saturating_add(actual_qual),
-                b'N' => {}  // this read abstains for this base
-                _ => unimplemented!()
+    // `prefix_lengths` are how many bases to skip at the start of each read
+    fn update_vote_vec(consensus_caller: &ConsensusCaller, phred_correction: u8, family: &mut FamilyVotes,
+                       pair: &FastqPair, prefix_lengths: (usize, usize)) {
+        let corrected = |qual: &[u8]| qual.iter().map(|quality| quality - phred_correction).collect::<Vec<u8>>();
+        consensus_caller.add_pair(
+            family,
+            (&pair.0.seq()[prefix_lengths.0..], &corrected(&pair.0.qual()[prefix_lengths.0..])),
+            (&pair.1.seq()[prefix_lengths.1..], &corrected(&pair.1.qual()[prefix_lengths.1..])),
+        );
+    }
+
+    // a consensus pair, named for its bin, with its family size and disagreement rate as fgbio's cD and cE tags. its
+    // tallies go to --vote-tallies
+    fn consensus_records(&mut self, key: &BinKey, consensus: ConsensusPair, source: &str)
+                         -> Result<FastqPair, GrebeError> {
+        let label = Self::bin_label(key);
+        let description = format!("constructed by grebe from {source} cD:i:{} cE:f:{:.4}",
+                                  consensus.size, consensus.disagreement_rate());
+        let description = match self.tag_headers {
+            true => Self::tag_description(key, Some(&description)),
+            false => description,
+        };
+
+        for (read_name, read) in [("forward", &consensus.reads.0), ("reverse", &consensus.reads.1)] {
+            for (position, (base, tally)) in read.seq.iter().zip(&read.tallies).enumerate() {
+                let [a, t, c, g] = tally.quality_totals;
+                self.record_writers.vote_tallies.write_row(
+                    &[&label, &read_name, &(position + 1), &a, &t, &c, &g, &tally.depth, &(*base as char)])?;
             }
         }
 
-        for (ind, (base, qual)) in pair.1.seq().iter()
-            .zip(pair.1.qual())
-            .enumerate() {
-            let vec_to_update = votes.1.get_mut(ind).unwrap();
-            let actual_qual = (qual - phred_correction) as QualityVoteTotal;
-
-            match base.to_ascii_uppercase() {
-                b'A' => vec_to_update.0 = vec_to_update.0.saturating_add(actual_qual),
-                b'T' => vec_to_update.1 = vec_to_update.1.saturating_add(actual_qual),
-                b'C' => vec_to_update.2 = vec_to_update.2.saturating_add(actual_qual),
-                b'G' => vec_to_update.3 = vec_to_update.3.saturating_add(actual_qual),
-                b'N' => {}  // this read abstains for this base
-                _ => unimplemented!()
+        let record = |read: ConsensusRead| fastq::Record::with_attrs(
+            &label,
+            Some(&description),
+            &read.seq,
+            &read.qual.into_iter().map(|quality| quality + self.phred_correction).collect::<Vec<u8>>(),
+        );
+        Ok((record(consensus.reads.0), record(consensus.reads.1)))
+    }
+
+    // bins in UMI order, so output does not depend on hashing or on the order pairs arrived in
+    fn sorted_bins<K: Ord, V>(bins: impl IntoIterator<Item = (K, V)>) -> impl Iterator<Item = (K, V)> {
+        bins.into_iter().sorted_unstable_by(|a, b| a.0.cmp(&b.0))
+    }
+
+    fn write_consensus(&mut self) -> Result<(), GrebeError> {
+        self.record_writers.vote_tallies.write_row(
+            &[&"family", &"read", &"position", &"A", &"T", &"C", &"G", &"depth", &"base"])?;
+
+        // kept in order as well, to find each family's other strand
+        let mut consensuses = BTreeMap::new();
+        for (key, family) in Self::sorted_bins(&self.quality_votes) {
+            if let Some(consensus) = self.consensus_caller.call_family(family, &mut self.consensus_count) {
+                consensuses.insert(key.clone(), consensus);
             }
         }
-    }
 
-    pub(crate) fn write_remaining(&mut self) {
-        for (umi, pairs) in
-            <HashMap<UMIVec, HashSet<(fastq::Record, fastq::Record)>> as Clone>::clone(&self.umi_bins).into_iter() {
-            match self.collision_resolution_method {
-                UMICollisionResolutionMethod::KeepFirst | UMICollisionResolutionMethod::None => {
-                    // these records are already on disk
+        while let Some((key, consensus)) = consensuses.pop_first() {
+            if !self.duplex {
+                let pair = self.consensus_records(&key, consensus, "quality voting")?;
+                unsafe { self.write_pair(pair)? };
+                continue;
+            }
+
+            // the first of the two strands to come up names the duplex
+            match consensuses.remove(&(key.0.clone(), duplex_partner(&key.1))) {
+                Some(other) => {
+                    let duplex = self.consensus_caller.call_duplex(consensus, other, &mut self.duplex_count);
+                    let pair = self.consensus_records(&key, duplex, "duplex quality voting")?;
+                    unsafe { self.write_pair(pair)? };
                 }
-                UMICollisionResolutionMethod::QualityVote => {
-                    let votes = self.quality_votes.get(&umi).unwrap();
-
-                    let total_from_index = |totals: &BaseQualityVotes, index: &u8| -> QualityVoteTotal {
-                        match index {
-                            0 => totals.0,
-                            1 => totals.1,
-                            2 => totals.2,
-                            3 => totals.3,
-                            _ => unreachable!()
-                        }
-                    };
+                None => {
+                    self.duplex_count.single_strand += 1;
+                    let pair = self.consensus_records(&key, consensus, "quality voting")?;
+                    self.record_writers.single_strand.0.write(pair.0.id(), pair.0.desc(), pair.0.seq(), pair.0.qual())?;
+                    self.record_writers.single_strand.1.write(pair.1.id(), pair.1.desc(), pair.1.seq(), pair.1.qual())?;
+                }
+            }
+        }
+        Ok(())
+    }
 
-                    // for a tuple of vote totals:
-                    let votes_to_winning_index = |totals: &BaseQualityVotes| -> u8 {
-                        // for each possible base (0..4), fetch the number of votes for that base
-                        (0..4).max_by_key(|i| total_from_index(totals, i)).unwrap()
+    // which of a family's pairs, in the order they arrived, --crm would have kept. when choosing each mate on its own,
+    // the forward read (the one carrying the UMI) decides for the pair here
+    fn representative(&self, key: &BinKey, family: &[FastqPair]) -> usize {
+        let method = self.collision_resolution_method;
+        match method {
+            UMICollisionResolutionMethod::KeepFirst => 0,
+            UMICollisionResolutionMethod::KeepLast => family.len() - 1,
+            UMICollisionResolutionMethod::KeepLongestLeft | UMICollisionResolutionMethod::KeepLongestRight |
+            UMICollisionResolutionMethod::KeepLongestExtend | UMICollisionResolutionMethod::KeepHighestQuality => {
+                (1..family.len()).fold(0, |kept, index| {
+                    let (old, new) = (&family[kept], &family[index]);
+                    let keeps_new = match self.per_mate {
+                        false => method.keeps_new(&self.quality_ranking, &self.extension, &[&old.0, &old.1],
+                                                  &[&new.0, &new.1]),
+                        true => method.keeps_new(&self.quality_ranking, &self.extension, &[&old.0], &[&new.0]),
                     };
+                    match keeps_new {
+                        true => index,
+                        false => kept,
+                    }
+                })
+            }
+            UMICollisionResolutionMethod::Majority => {
+                let mut counts = SequenceCounts::default();
+                for pair in family {
+                    counts.add(pair, key.0.len() + key.1.len(), self.phred_correction);
+                }
+                counts.majority(self.majority_tie_break).first_seen
+            }
+            _ => unreachable!()
+        }
+    }
 
-                    let select_winner = |index: u8| -> u8 {
-                        match index {  // now convert the winning index to a base
-                            0 => b'A',
-                            1 => b'T',
-                            2 => b'C',
-                            3 => b'G',
-                            _ => unreachable!()
-                        }
-                    };
+    // move a bin to another key no pair has been binned under yet, as when a UMI with wildcards turns out to have a
+    // plain UMI to stand for it. pairs already written (with --crm none) keep the key they were written with
+    pub(crate) fn rebin(&mut self, from: &BinKey, to: &BinKey) {
+        if let Some(pairs) = self.umi_bins.remove(from) {
+            self.umi_bins.insert(to.clone(), pairs);
+        }
+        if let Some(family) = self.quality_votes.remove(from) {
+            self.quality_votes.insert(to.clone(), family);
+        }
+        if let Some(family) = self.sequence_counts.remove(from) {
+            self.sequence_counts.insert(to.clone(), family);
+        }
+        if let Some(family) = self.families.remove(from) {
+            self.families.insert(to.clone(), family);
+        }
+    }
 
-                    let resolved: (Vec<u8>, Vec<u8>) = (
-                        votes.0.iter().map(votes_to_winning_index).collect(),
-                        votes.1.iter().map(votes_to_winning_index).collect()
-                    );
-
-                    // TODO: forgot you can do math on probabilities
-                    unsafe {
-                        self.write_pair((
-                            fastq::Record::with_attrs(
-                                std::str::from_utf8_unchecked(&*umi),
-                                Option::from("constructed by grebe from quality voting"),
-                                &resolved.0.into_iter().map(select_winner).collect::<Vec<u8>>(),
-                                // for each vote 4-tuple: get the best index, then get the total at that index
-                                votes.0.iter()
-                                    .map(|v| total_from_index(v, &votes_to_winning_index(v)))
-                                    .map(|total| (total + self.phred_correction as QualityVoteTotal) as u8)
-                                    .collect::<Vec<u8>>()
-                                    .as_slice(),
-                            ),
-                            fastq::Record::with_attrs(
-                                std::str::from_utf8_unchecked(&*umi),
-                                Option::from("constructed by grebe from quality voting"),
-                                &resolved.1.into_iter().map(select_winner).collect::<Vec<u8>>(),
-                                votes.1.iter()
-                                    .map(|v| total_from_index(v, &votes_to_winning_index(v)))
-                                    .map(|total| (total + self.phred_correction as QualityVoteTotal) as u8)
-                                    .collect::<Vec<u8>>()
-                                    .as_slice(),
-                            )
-                        ));
+    // pairs marked as duplicates: every pair not chosen as its family's representative
+    pub(crate) fn duplicates_marked(&self) -> usize {
+        self.families.values().map(|family| family.len() - 1).sum()
+    }
+
+    // every pair, with its family's number (fgbio's MI tag), the family's size (cD, as on consensus reads) and XD:i:1
+    // unless it is the pair --crm would have kept. the bin's cell barcode and UMI are always added as CB and UB
+    fn write_marked(&mut self) -> Result<(), GrebeError> {
+        for (id, (key, family)) in Self::sorted_bins(std::mem::take(&mut self.families)).enumerate() {
+            let representative = self.representative(&key, &family);
+            let family_size = family.len();
+            for (index, pair) in family.into_iter().enumerate() {
+                let tags = format!("MI:Z:{id} cD:i:{family_size} XD:i:{}", (index != representative) as u8);
+                let tag = |record: &fastq::Record| fastq::Record::with_attrs(
+                    record.id(),
+                    Some(&[Self::tag_description(&key, record.desc()), tags.clone()].join(" ")),
+                    record.seq(),
+                    record.qual(),
+                );
+                unsafe { self.write_pair((tag(&pair.0), tag(&pair.1)))? };
+            }
+        }
+        Ok(())
+    }
+
+    // each family's most common sequences, written as the first pair seen with them, with how many of the family's
+    // pairs had them (XM:i:) and the family's size (cD:i:)
+    fn write_majority(&mut self) -> Result<(), GrebeError> {
+        for (key, family) in Self::sorted_bins(std::mem::take(&mut self.sequence_counts)) {
+            let majority = family.majority(self.majority_tie_break);
+            let tags = format!("XM:i:{} cD:i:{}", majority.count, family.size);
+            let description = |record: &fastq::Record| match self.tag_headers {
+                true => Self::tag_description(&key, record.desc()),
+                false => record.desc().unwrap_or_default().to_owned(),
+            };
+            let tag = |record: &fastq::Record| fastq::Record::with_attrs(
+                record.id(),
+                Some(&[description(record), tags.clone()].into_iter().filter(|part| !part.is_empty()).join(" ")),
+                record.seq(),
+                record.qual(),
+            );
+            unsafe { self.write_pair((tag(&majority.pair.0), tag(&majority.pair.1)))? };
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_remaining(&mut self) -> Result<(), GrebeError> {
+        if self.mark_duplicates {
+            self.write_marked()?;
+            return self.record_writers.flush();
+        }
+        match self.collision_resolution_method {
+            UMICollisionResolutionMethod::QualityVote => self.write_consensus()?,
+            UMICollisionResolutionMethod::Majority => self.write_majority()?,
+            _ => {}
+        }
+
+        for (key, pairs) in Self::sorted_bins(self.umi_bins.clone()) {
+            match self.collision_resolution_method {
+                UMICollisionResolutionMethod::None => {
+                    // these records are already on disk
+                }
+                UMICollisionResolutionMethod::KeepFirst => {
+                    // so are these, except the first of a bin whose UMI had wildcards when it was read
+                    for pair in pairs {
+                        match self.tag_headers {
+                            true => unsafe { self.write_pair(Self::tag_pair(&key, &pair))? },
+                            false => unsafe { self.write_pair(pair)? },
+                        }
                     }
                 }
+                UMICollisionResolutionMethod::QualityVote => {
+                    // already written above, as duplexes may need any two families at once
+                }
+                UMICollisionResolutionMethod::Majority => {
+                    // already written above
+                }
                 _ => unsafe {
                     // conflict resolution has already selected a single read
-                    self.write_pair(pairs.iter().exactly_one().unwrap().clone());
+                    let pair = pairs.iter().exactly_one().unwrap();
+                    match self.tag_headers {
+                        true => self.write_pair(Self::tag_pair(&key, pair))?,
+                        false => self.write_pair(pair.clone())?,
+                    }
                 }
             };
         }
+        self.record_writers.flush()
     }
 }
\ No newline at end of file
@@ -1,12 +1,14 @@
 use std::fs::File;
 use std::io;
 use std::io::{BufRead, BufReader, Read};
-use std::path::PathBuf;
-use std::process::exit;
+use std::path::{Path, PathBuf};
 
 use bio::io::fastq;
 use flate2::bufread::MultiGzDecoder;
 
+use crate::error::GrebeError;
+
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum ReaderMaybeGzip {
     GZIP(BufReader<MultiGzDecoder<BufReader<File>>>),
     UNCOMPRESSED(BufReader<File>),
@@ -44,34 +46,59 @@ impl BufRead for ReaderMaybeGzip {
 pub(crate) fn reader_maybe_gzip(path_buf: &PathBuf) -> Result<(fastq::Reader<ReaderMaybeGzip>, bool), io::Error> {
     let mut file = File::open(path_buf)?;
     let mut magic = [0; 2];
-    file.read(&mut magic[..])?;
+    let magic_len = file.read(&mut magic[..])?;
 
     let reopen = BufReader::new(File::open(path_buf)?);
 
-    if magic.eq(&[0x1f, 0x8b]) {
+    if magic_len == magic.len() && magic.eq(&[0x1f, 0x8b]) {
         Ok((fastq::Reader::from_bufread(ReaderMaybeGzip::GZIP(BufReader::new(MultiGzDecoder::new(reopen)))), true))
     } else {
         Ok((fastq::Reader::from_bufread(ReaderMaybeGzip::UNCOMPRESSED(reopen)), false))
     }
 }
 
-fn reader_from_path(maybe_path_buf: Option<&PathBuf>, silent: bool) -> fastq::Reader<ReaderMaybeGzip> {
+fn reader_from_path(maybe_path_buf: Option<&PathBuf>, silent: bool)
+                    -> Result<fastq::Reader<ReaderMaybeGzip>, GrebeError> {
     match maybe_path_buf {
         Some(path_buf) => match reader_maybe_gzip(path_buf) {
             Ok((result, was_compressed)) => {
                 if was_compressed && !silent { eprintln!("info: parsing {} as a gzip", path_buf.display()) }
-                result
-            }
-            Err(_) => {
-                eprintln!("couldn't open input {} for reading", path_buf.display());
-                exit(1);
+                Ok(result)
             }
+            Err(err) => Err(GrebeError::Open(path_buf.clone(), err)),
         }
-        None => fastq::Reader::from_bufread(ReaderMaybeGzip::NULL(BufReader::new(io::empty())))
+        None => Ok(fastq::Reader::from_bufread(ReaderMaybeGzip::NULL(BufReader::new(io::empty()))))
     }
 }
 
 pub(crate) fn make_reader_pair(input_paths: (Option<&PathBuf>, Option<&PathBuf>), silent: bool)
-                               -> (fastq::Reader<ReaderMaybeGzip>, fastq::Reader<ReaderMaybeGzip>) {
-    (reader_from_path(input_paths.0, silent), reader_from_path(input_paths.1, silent))
+                               -> Result<(fastq::Reader<ReaderMaybeGzip>, fastq::Reader<ReaderMaybeGzip>), GrebeError> {
+    Ok((reader_from_path(input_paths.0, silent)?, reader_from_path(input_paths.1, silent)?))
+}
+
+// what went wrong reading record `record_number` (from 1) of a file. a gzip cut off partway (or a plain file cut off
+// mid-record) shows up as an unexpected EOF or an incomplete record
+pub(crate) fn read_error(err: fastq::Error, path_buf: &Path, record_number: usize) -> GrebeError {
+    match err {
+        fastq::Error::IncompleteRecord => GrebeError::TruncatedInput(path_buf.to_owned(), record_number),
+        fastq::Error::ReadError(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            GrebeError::TruncatedInput(path_buf.to_owned(), record_number)
+        }
+        fastq::Error::ReadError(err) => GrebeError::Read(path_buf.to_owned(), record_number, err),
+        fastq::Error::FileOpen { source, .. } => GrebeError::Open(path_buf.to_owned(), source),
+        err => GrebeError::MalformedRecord(path_buf.to_owned(), record_number, err.to_string()),
+    }
+}
+
+// a reader that fails keeps failing on every record after, so counting stops at the first failure
+pub(crate) fn count_records(reader: fastq::Reader<ReaderMaybeGzip>, maybe_path_buf: Option<&PathBuf>)
+                            -> Result<usize, GrebeError> {
+    let mut count = 0;
+    for record in reader.records() {
+        count += 1;
+        if let Err(err) = record {
+            return Err(read_error(err, maybe_path_buf.map_or(Path::new(""), PathBuf::as_path), count));
+        }
+    }
+    Ok(count)
 }
@@ -1,20 +1,41 @@
 use bio::io::fastq;
 
-use crate::writer::WriterMaybeGzip;
+use crate::consensus::BaseVotes;
+use crate::error::GrebeError;
+use crate::writer::{RecordWriter, TableWriter};
 
 pub(crate) type FastqPair = (fastq::Record, fastq::Record);
 pub(crate) type UMIVec = Vec<u8>;
+// (cell barcode, UMI); the barcode is empty unless -b is given
+pub(crate) type BinKey = (UMIVec, UMIVec);
 pub(crate) type QualityVoteTotal = u64;
 
+#[derive(Default)]
 pub(crate) struct OutputWriters {
-    pub(crate) paired: (fastq::Writer<WriterMaybeGzip>, fastq::Writer<WriterMaybeGzip>),
-    pub(crate) unpaired: (fastq::Writer<WriterMaybeGzip>, fastq::Writer<WriterMaybeGzip>),
+    pub(crate) paired: (RecordWriter, RecordWriter),
+    pub(crate) unpaired: (RecordWriter, RecordWriter),
+    pub(crate) artifact: (RecordWriter, RecordWriter),
+    // single-strand consensuses that never found their other strand, with --duplex
+    pub(crate) single_strand: (RecordWriter, RecordWriter),
+    // what each consensus base was voted from, with --vote-tallies
+    pub(crate) vote_tallies: TableWriter,
 }
 
+impl OutputWriters {
+    pub(crate) fn flush(&mut self) -> Result<(), GrebeError> {
+        for writer in [&mut self.paired.0, &mut self.paired.1, &mut self.unpaired.0, &mut self.unpaired.1,
+            &mut self.artifact.0, &mut self.artifact.1, &mut self.single_strand.0, &mut self.single_strand.1] {
+            writer.flush()?;
+        }
+        self.vote_tallies.flush()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum WhichRead {
     FORWARD,
     REVERSE,
 }
 
-pub(crate) type BaseQualityVotes = (QualityVoteTotal, QualityVoteTotal, QualityVoteTotal, QualityVoteTotal);
-pub(crate) type QualityVoteVec = Vec<BaseQualityVotes>;
+pub(crate) type QualityVoteVec = Vec<BaseVotes>;
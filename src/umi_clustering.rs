@@ -0,0 +1,280 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+
+use itertools::Itertools;
+use strum::VariantArray;
+
+use crate::types::{BinKey, QualityVoteTotal, UMIVec};
+use crate::umi_distance::UMIDistance;
+use crate::umi_index::UMIIndex;
+
+// UMIs seen fewer times than this fraction of the median count are discarded by --umi-clustering percentile
+const PERCENTILE_CUTOFF: f64 = 0.01;
+
+#[derive(Clone, Copy, PartialEq, VariantArray)]
+pub(crate) enum UMIClusteringMethod {
+    None,
+    Unique,
+    Percentile,
+    Cluster,
+    Adjacency,
+    Directional,
+}
+
+#[derive(Default)]
+pub(crate) struct UMITally {
+    pub(crate) count: usize,
+    // summed over every base of every copy; only populated if qualities are collected
+    pub(crate) quality_total: QualityVoteTotal,
+}
+
+impl UMITally {
+    fn mean_quality(&self) -> f64 {
+        self.quality_total as f64 / self.count as f64
+    }
+}
+
+// first-pass state: every UMI seen on a good pair (under each cell barcode) and how often
+pub(crate) struct UMIClusterer {
+    pub(crate) method: UMIClusteringMethod,
+    pub(crate) radius: usize,
+    pub(crate) distance: UMIDistance,
+    pub(crate) collect_qualities: bool,
+    pub(crate) phred_correction: u8,
+    pub(crate) tallies: HashMap<BinKey, UMITally>,
+}
+
+impl UMIClusterer {
+    pub(crate) fn count(&mut self, key: &BinKey, umi_qualities: &[u8]) {
+        let tally = self.tallies.entry(key.clone()).or_default();
+        tally.count += 1;
+        if self.collect_qualities {
+            tally.quality_total += umi_qualities.iter()
+                .map(|q| q.saturating_sub(self.phred_correction) as QualityVoteTotal)
+                .sum::<QualityVoteTotal>();
+        }
+    }
+
+    // most abundant first, then (if collected) highest quality, then on sequence so nothing below depends on read
+    // order; the first UMI of each cluster in this order is its representative
+    fn umis_by_abundance<'a>(mut ordered: Vec<(&'a UMIVec, &'a UMITally)>) -> Vec<(&'a UMIVec, &'a UMITally)> {
+        ordered.sort_unstable_by(|a, b| b.1.count.cmp(&a.1.count)
+            .then_with(|| b.1.mean_quality().partial_cmp(&a.1.mean_quality()).unwrap_or(Ordering::Equal))
+            .then_with(|| a.0.cmp(b.0)));
+        ordered
+    }
+
+    // for each UMI (by index into `ordered`), every other UMI within the radius
+    fn neighbours(&self, ordered: &[(&UMIVec, &UMITally)]) -> Vec<Vec<usize>> {
+        let mut index = UMIIndex::new(
+            ordered.first().map_or(0, |(umi, _)| umi.len()), self.radius, self.distance);
+        for (umi, _) in ordered {
+            index.insert(umi);
+        }
+        let positions = ordered.iter().enumerate()
+            .map(|(position, (umi, _))| (*umi, position))
+            .collect::<HashMap<_, _>>();
+
+        ordered.iter().enumerate()
+            .map(|(position, (umi, _))| {
+                index.find_within(umi).into_iter()
+                    .map(|(_, other)| positions[other])
+                    .filter(|other_position| *other_position != position)
+                    // keep the abundance order the methods below rely on
+                    .sorted_unstable()
+                    .collect()
+            })
+            .collect()
+    }
+
+    // everything reachable from each not-yet-claimed UMI, most abundant first, is represented by it
+    fn connected_components(edges: &[Vec<usize>]) -> Vec<usize> {
+        let mut representative: Vec<Option<usize>> = vec![None; edges.len()];
+        for root in 0..edges.len() {
+            if representative[root].is_some() {
+                continue;
+            }
+
+            representative[root] = Some(root);
+            let mut queue = VecDeque::from([root]);
+            while let Some(node) = queue.pop_front() {
+                for &neighbour in &edges[node] {
+                    if representative[neighbour].is_none() {
+                        representative[neighbour] = Some(root);
+                        queue.push_back(neighbour);
+                    }
+                }
+            }
+        }
+
+        representative.into_iter().map(Option::unwrap).collect()
+    }
+
+    // map every UMI counted to the UMI representing its cluster; UMIs left out should be discarded. UMIs under
+    // different cell barcodes come from different molecules, so each barcode is clustered on its own
+    pub(crate) fn cluster(&self) -> HashMap<BinKey, UMIVec> {
+        self.tallies.iter()
+            .into_group_map_by(|((barcode, _), _)| barcode)
+            .into_iter()
+            .flat_map(|(barcode, tallies)| {
+                let ordered = Self::umis_by_abundance(
+                    tallies.into_iter().map(|((_, umi), tally)| (umi, tally)).collect());
+                self.cluster_barcode(&ordered).into_iter()
+                    .map(|(umi, representative)| ((barcode.clone(), umi), representative))
+            })
+            .collect()
+    }
+
+    // for each UMI (by index into `ordered`), the UMI representing it under one of the graph-based methods
+    fn graph_representatives(&self, ordered: &[(&UMIVec, &UMITally)]) -> Vec<usize> {
+        match self.method {
+            UMIClusteringMethod::Adjacency => {
+                // each UMI not yet claimed leads a group of itself and its unclaimed direct neighbours
+                let edges = self.neighbours(ordered);
+                let mut representative = vec![None; ordered.len()];
+                for lead in 0..ordered.len() {
+                    if representative[lead].is_some() {
+                        continue;
+                    }
+
+                    representative[lead] = Some(lead);
+                    for &neighbour in &edges[lead] {
+                        representative[neighbour].get_or_insert(lead);
+                    }
+                }
+                representative.into_iter().map(Option::unwrap).collect()
+            }
+            UMIClusteringMethod::Directional => {
+                // only let a UMI absorb neighbours at most about half as abundant as it
+                let edges = self.neighbours(ordered).into_iter().enumerate()
+                    .map(|(index, neighbours)| neighbours.into_iter()
+                        .filter(|&other| ordered[index].1.count + 1 >= 2 * ordered[other].1.count)
+                        .collect())
+                    .collect::<Vec<Vec<usize>>>();
+                Self::connected_components(&edges)
+            }
+            _ => Self::connected_components(&self.neighbours(ordered)),
+        }
+    }
+
+    fn cluster_barcode(&self, ordered: &[(&UMIVec, &UMITally)]) -> Vec<(UMIVec, UMIVec)> {
+        let representative: Vec<Option<usize>> = match self.method {
+            UMIClusteringMethod::None | UMIClusteringMethod::Unique => (0..ordered.len()).map(Some).collect(),
+            UMIClusteringMethod::Percentile => {
+                let median = match ordered.len() {
+                    0 => 0,
+                    // `ordered` is already sorted by count
+                    n => ordered[n / 2].1.count,
+                };
+                let cutoff = median as f64 * PERCENTILE_CUTOFF;
+                ordered.iter().enumerate()
+                    .map(|(index, (_, tally))| (tally.count as f64 >= cutoff).then_some(index))
+                    .collect()
+            }
+            UMIClusteringMethod::Cluster | UMIClusteringMethod::Adjacency | UMIClusteringMethod::Directional => {
+                // a UMI with wildcards is within the radius of everything its Ns could stand for, so as a node it
+                // could bridge clusters that are otherwise apart. cluster the plain UMIs alone, then attach each
+                // wildcard UMI to the nearest representative (or leave it on its own if none is close enough)
+                let (plain, wildcard): (Vec<usize>, Vec<usize>) = (0..ordered.len())
+                    .partition(|index| !ordered[*index].0.contains(&b'N'));
+                let plain_ordered = plain.iter().map(|index| ordered[*index]).collect::<Vec<_>>();
+                let mut representative = vec![None; ordered.len()];
+                for (index, root) in plain.iter().zip(self.graph_representatives(&plain_ordered)) {
+                    representative[*index] = Some(plain[root]);
+                }
+
+                let mut representatives = UMIIndex::new(
+                    ordered.first().map_or(0, |(umi, _)| umi.len()), self.radius, self.distance);
+                let mut positions = HashMap::new();
+                for index in representative.iter().flatten().unique() {
+                    representatives.insert(ordered[*index].0);
+                    positions.insert(ordered[*index].0, *index);
+                }
+                for index in wildcard {
+                    representative[index] = Some(representatives.find_nearest(ordered[index].0)
+                        .map_or(index, |found| positions[found]));
+                }
+                representative
+            }
+        };
+
+        ordered.iter().zip(representative)
+            .filter_map(|((umi, _), root)| root.map(|root| ((*umi).clone(), ordered[root].0.clone())))
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clusterer(method: UMIClusteringMethod, counts: &[(&[u8], usize)]) -> UMIClusterer {
+        let mut clusterer = UMIClusterer {
+            method,
+            radius: 1,
+            distance: UMIDistance::Hamming,
+            collect_qualities: false,
+            phred_correction: 33,
+            tallies: Default::default(),
+        };
+        for (umi, count) in counts {
+            for _ in 0..*count {
+                clusterer.count(&(vec![], umi.to_vec()), &[]);
+            }
+        }
+        clusterer
+    }
+
+    fn representative(clusters: &HashMap<BinKey, UMIVec>, umi: &[u8]) -> Option<Vec<u8>> {
+        clusters.get(&(vec![], umi.to_vec())).cloned()
+    }
+
+    #[test]
+    fn wildcard_umis_do_not_bridge_clusters() {
+        for method in [UMIClusteringMethod::Cluster, UMIClusteringMethod::Adjacency, UMIClusteringMethod::Directional] {
+            let clusters = clusterer(method, &[(b"AAAAAAAA", 20), (b"CCAAAAAA", 20), (b"NCAAAAAA", 1)]).cluster();
+            assert_eq!(representative(&clusters, b"AAAAAAAA"), Some(b"AAAAAAAA".to_vec()));
+            assert_eq!(representative(&clusters, b"CCAAAAAA"), Some(b"CCAAAAAA".to_vec()));
+            assert_eq!(representative(&clusters, b"NCAAAAAA"), Some(b"CCAAAAAA".to_vec()));
+        }
+    }
+
+    #[test]
+    fn directional_absorbs_up_to_about_half_as_abundant() {
+        // 10 + 1 >= 2 * 5, but not 2 * 6
+        let clusters = clusterer(UMIClusteringMethod::Directional, &[(b"AAAAAAAA", 10), (b"AAAAAAAC", 5)]).cluster();
+        assert_eq!(representative(&clusters, b"AAAAAAAC"), Some(b"AAAAAAAA".to_vec()));
+        let clusters = clusterer(UMIClusteringMethod::Directional, &[(b"AAAAAAAA", 10), (b"AAAAAAAC", 6)]).cluster();
+        assert_eq!(representative(&clusters, b"AAAAAAAC"), Some(b"AAAAAAAC".to_vec()));
+    }
+
+    #[test]
+    fn directional_follows_edges_transitively() {
+        let clusters = clusterer(UMIClusteringMethod::Directional,
+                                 &[(b"AAAAAAAA", 10), (b"AAAAAAAC", 5), (b"AAAAAACC", 2)]).cluster();
+        assert_eq!(representative(&clusters, b"AAAAAACC"), Some(b"AAAAAAAA".to_vec()));
+        // counts too close for an edge from the middle UMI to the last
+        let clusters = clusterer(UMIClusteringMethod::Directional,
+                                 &[(b"AAAAAAAA", 10), (b"AAAAAAAC", 5), (b"AAAAAACC", 4)]).cluster();
+        assert_eq!(representative(&clusters, b"AAAAAACC"), Some(b"AAAAAACC".to_vec()));
+    }
+
+    #[test]
+    fn adjacency_only_merges_direct_neighbours_where_cluster_merges_components() {
+        // a chain: each UMI one away from the next, the ends two apart
+        let counts: &[(&[u8], usize)] = &[(b"AAAAAAAA", 10), (b"AAAAAAAC", 5), (b"AAAAAACC", 1), (b"GGGGGGGG", 3)];
+
+        let clusters = clusterer(UMIClusteringMethod::Cluster, counts).cluster();
+        for umi in [b"AAAAAAAA", b"AAAAAAAC", b"AAAAAACC"] {
+            assert_eq!(representative(&clusters, umi), Some(b"AAAAAAAA".to_vec()));
+        }
+        assert_eq!(representative(&clusters, b"GGGGGGGG"), Some(b"GGGGGGGG".to_vec()));
+
+        let clusters = clusterer(UMIClusteringMethod::Adjacency, counts).cluster();
+        assert_eq!(representative(&clusters, b"AAAAAAAC"), Some(b"AAAAAAAA".to_vec()));
+        // its only neighbour was already claimed, so it leads a group of its own
+        assert_eq!(representative(&clusters, b"AAAAAACC"), Some(b"AAAAAACC".to_vec()));
+        assert_eq!(representative(&clusters, b"GGGGGGGG"), Some(b"GGGGGGGG".to_vec()));
+    }
+}
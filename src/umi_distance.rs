@@ -0,0 +1,77 @@
+use std::cmp::min;
+
+use strum::VariantArray;
+
+#[derive(Clone, Copy, PartialEq, VariantArray)]
+pub(crate) enum UMIDistance {
+    Hamming,
+    Levenshtein,
+}
+
+impl UMIDistance {
+    pub(crate) fn function(&self) -> fn(&[u8], &[u8]) -> u64 {
+        match self {
+            UMIDistance::Hamming => umi_hamming,
+            UMIDistance::Levenshtein => umi_levenshtein,
+        }
+    }
+
+    // how far an error can move the template start away from the end of the fixed-length UMI slice
+    pub(crate) fn max_shift(&self, radius: usize) -> usize {
+        match self {
+            UMIDistance::Hamming => 0,
+            UMIDistance::Levenshtein => radius,
+        }
+    }
+}
+
+// N (including low-quality bases masked to N) is a wildcard and agrees with anything
+fn bases_agree(a: &u8, b: &u8) -> bool {
+    a.eq_ignore_ascii_case(b) || a.eq_ignore_ascii_case(&b'N') || b.eq_ignore_ascii_case(&b'N')
+}
+
+pub(crate) fn umi_hamming(a: &[u8], b: &[u8]) -> u64 {
+    a.iter().zip(b).filter(|(a, b)| !bases_agree(a, b)).count() as u64
+}
+
+// edit distance table of `a` against `b`, one row per base of `a` (plus the empty prefix)
+fn edit_table(a: &[u8], b: &[u8]) -> Vec<Vec<u64>> {
+    let mut table = vec![(0..=b.len() as u64).collect::<Vec<_>>()];
+    for i in 1..=a.len() {
+        let previous = &table[i - 1];
+        let mut current = vec![i as u64; b.len() + 1];
+        for j in 1..=b.len() {
+            current[j] = min(
+                previous[j - 1] + !bases_agree(&a[i - 1], &b[j - 1]) as u64,
+                min(previous[j], current[j - 1]) + 1,
+            );
+        }
+        table.push(current);
+    }
+    table
+}
+
+// edit distance between two UMIs cut from fixed-length slices. an indel shifts everything after it, pushing bases past
+// the end of one slice or pulling template bases into it, so whatever overhangs the end of either UMI is not counted
+pub(crate) fn umi_levenshtein(a: &[u8], b: &[u8]) -> u64 {
+    let table = edit_table(a, b);
+    let last_row = table.last().unwrap().iter().copied();
+    let last_column = table.iter().map(|row| *row.last().unwrap());
+    last_row.chain(last_column).min().unwrap()
+}
+
+// how many bases at the start of `read` make up `umi`, allowing `max_shift` bases of indel. `anchor` is whatever should
+// follow the UMI (e.g. the start of the forward primer), if known; without it, an indel in the last few bases of the
+// UMI can't be told apart from a mismatch. ties favour no shift, then the shorter UMI
+pub(crate) fn umi_template_start(umi: &[u8], anchor: &[u8], read: &[u8], max_shift: usize) -> usize {
+    if max_shift == 0 {
+        return umi.len();
+    }
+
+    let expected = [umi, anchor].concat();
+    let window = &read[..min(read.len(), expected.len() + max_shift)];
+    let last_row = edit_table(&expected, window).pop().unwrap();
+    (expected.len().saturating_sub(max_shift)..last_row.len())
+        .min_by_key(|j| (last_row[*j], *j != expected.len(), *j))
+        .map_or(umi.len(), |j| j.saturating_sub(anchor.len()))
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+
+use crate::types::UMIVec;
+use crate::umi_distance::UMIDistance;
+
+// segments are packed two bits per base, so can be at most this long (the longest cell barcode)
+const MAX_SEGMENT_LENGTH: usize = 32;
+
+// multi-index over fixed-length UMIs: split every UMI into a few segments and hash each one. two UMIs within the
+// radius must have at least one segment within a few substitutions of each other (pigeonhole), so a lookup only has to
+// try those substitutions of its own segments, not compare against every known UMI. long segments keep each bucket
+// small however many UMIs are known; up to 2 substitutions per segment keeps the number of variants tried in check
+pub(crate) struct UMIIndex {
+    radius: usize,
+    // with indels, a segment can turn up this far from where it sits in the indexed UMI
+    max_shift: usize,
+    // [start, end) of each segment
+    segments: Vec<(usize, usize)>,
+    // how many substitutions a lookup tries within each of its segments
+    segment_errors: usize,
+    // one table per segment: packed segment -> indices into `umis`
+    tables: Vec<HashMap<u64, Vec<usize>, BuildHasherDefault<SegmentHasher>>>,
+    umis: Vec<UMIVec>,
+    // false once a UMI has been removed; its entries in `tables` are left behind and skipped over
+    live: Vec<bool>,
+    lookup: HashMap<UMIVec, usize>,
+    distance: fn(&[u8], &[u8]) -> u64,
+}
+
+// a lookup hashes hundreds of segment variants, so SipHash's resistance to chosen keys costs more than it's worth here
+#[derive(Default)]
+struct SegmentHasher(u64);
+
+impl Hasher for SegmentHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_u64(*byte as u64);
+        }
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        let mixed = (self.0 ^ value).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        self.0 = mixed ^ (mixed >> 29);
+    }
+}
+
+// a segment two bits per base, and which of its bases are wildcards. other bases than ACGT pack as A, which can only
+// make segments look closer than they are; the distance check afterwards sorts that out
+fn pack(segment: &[u8]) -> (u64, u64) {
+    segment.iter().enumerate().fold((0, 0), |(packed, wildcards), (position, base)| {
+        match base.to_ascii_uppercase() {
+            b'N' => (packed, wildcards | 1 << position),
+            base => (packed | (b"ACGT".iter().position(|known| *known == base).unwrap_or(0) as u64) << (2 * position),
+                     wildcards),
+        }
+    })
+}
+
+impl UMIIndex {
+    pub(crate) fn new(umi_length: usize, radius: usize, umi_distance: UMIDistance) -> Self {
+        let max_shift = umi_distance.max_shift(radius);
+        let (segment_count, segment_errors) = match max_shift {
+            // with s segments, one of them has at most radius / s substitutions
+            0 => {
+                let segment_count = (radius + 1).div_ceil(3);
+                (segment_count, radius / segment_count)
+            }
+            // a segment an indel lands in can't be found by substituting bases, so every error needs a segment of its
+            // own. a shift can also push the last segment partly off the end, so it gets no guarantee either
+            _ => (radius + 2, 0),
+        };
+        // with more errors than bases to split between segments, no segment is guaranteed to survive. sequences too
+        // long to pack are compared against every known one too
+        let segment_count = match segment_count <= umi_length
+            && umi_length.div_ceil(segment_count) <= MAX_SEGMENT_LENGTH {
+            true => segment_count,
+            false => 0,
+        };
+        let segments = (0..segment_count)
+            .map(|i| (i * umi_length / segment_count, (i + 1) * umi_length / segment_count))
+            .collect::<Vec<_>>();
+
+        UMIIndex {
+            radius,
+            max_shift,
+            tables: vec![Default::default(); segments.len()],
+            segments,
+            segment_errors,
+            umis: vec![],
+            live: vec![],
+            lookup: Default::default(),
+            distance: umi_distance.function(),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, umi: &UMIVec) {
+        if self.lookup.contains_key(umi) {
+            return;
+        }
+
+        let index = self.umis.len();
+        for ((start, end), table) in self.segments.iter().zip(self.tables.iter_mut()) {
+            // file UMIs with wildcards under everything they could be, so plain UMIs can find them too
+            let (packed, wildcards) = pack(&umi[*start..*end]);
+            Self::expand_wildcards(packed, wildcards, &mut |segment| table.entry(segment).or_default().push(index));
+        }
+        self.umis.push(umi.clone());
+        self.live.push(true);
+        self.lookup.insert(umi.clone(), index);
+    }
+
+    pub(crate) fn remove(&mut self, umi: &UMIVec) {
+        if let Some(index) = self.lookup.remove(umi) {
+            self.live[index] = false;
+        }
+    }
+
+    // every segment the wildcards in this one could stand for
+    fn expand_wildcards(packed: u64, wildcards: u64, visit: &mut impl FnMut(u64)) {
+        if wildcards == 0 {
+            return visit(packed);
+        }
+
+        let shift = 2 * wildcards.trailing_zeros();
+        for base in 0..4 {
+            Self::expand_wildcards(packed & !(3 << shift) | base << shift, wildcards & (wildcards - 1), visit);
+        }
+    }
+
+    // every segment at most `errors` substitutions from this one, changing bases from `from` on. wildcards already
+    // match every base, so are left for `expand_wildcards`
+    fn substitute(packed: u64, wildcards: u64, from: usize, length: usize, errors: usize,
+                  visit: &mut impl FnMut(u64)) {
+        visit(packed);
+        if errors == 0 {
+            return;
+        }
+
+        for position in (from..length).filter(|position| wildcards & 1 << position == 0) {
+            for change in 1..4 {
+                Self::substitute(packed ^ change << (2 * position), wildcards, position + 1, length, errors - 1, visit);
+            }
+        }
+    }
+
+    fn candidates(&self, umi: &[u8]) -> Vec<usize> {
+        if self.segments.is_empty() {
+            return (0..self.umis.len()).filter(|index| self.live[*index]).collect();
+        }
+
+        let mut candidates = vec![];
+        for ((start, end), table) in self.segments.iter().zip(&self.tables) {
+            let mut collect = |segment| candidates.extend(table.get(&segment).into_iter().flatten());
+            for shifted in start.saturating_sub(self.max_shift)..=start + self.max_shift {
+                let Some(window) = umi.get(shifted..shifted + end - start) else {
+                    continue;
+                };
+                let (packed, wildcards) = pack(window);
+                Self::substitute(packed, wildcards, 0, window.len(), self.segment_errors, &mut |substituted| {
+                    Self::expand_wildcards(substituted, wildcards, &mut collect)
+                });
+            }
+        }
+        candidates.retain(|index| self.live[*index]);
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates
+    }
+
+    // every known UMI at most the radius away, with its distance
+    pub(crate) fn find_within(&self, umi: &[u8]) -> Vec<(u64, &UMIVec)> {
+        self.candidates(umi).into_iter()
+            .map(|index| ((self.distance)(&self.umis[index], umi), &self.umis[index]))
+            .filter(|(distance, _)| *distance <= self.radius as u64)
+            .collect()
+    }
+
+    // the closest known UMI within the radius, preferring UMIs with fewer wildcards, then on sequence so the result
+    // does not depend on insertion order
+    pub(crate) fn find_nearest(&self, umi: &[u8]) -> Option<&UMIVec> {
+        if let Some(index) = self.lookup.get(umi) {
+            return Some(&self.umis[*index]);
+        }
+
+        let wildcards = |found: &UMIVec| found.iter().filter(|base| **base == b'N').count();
+        self.find_within(umi).into_iter()
+            .min_by(|a, b| a.0.cmp(&b.0)
+                .then_with(|| wildcards(a.1).cmp(&wildcards(b.1)))
+                .then_with(|| a.1.cmp(b.1)))
+            .map(|(_, found)| found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use super::*;
+
+    // reproducible UMIs, some with wildcards, without pulling in a random number crate
+    fn umis(count: usize, length: usize, wildcard_every: usize) -> Vec<UMIVec> {
+        let mut state = 0x2545f491u64;
+        (0..count)
+            .map(|index| (0..length)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                    b"ACGT"[(state >> 33) as usize % 4]
+                })
+                .enumerate()
+                .map(|(position, base)| match index % wildcard_every == 0 && position == index % length {
+                    true => b'N',
+                    false => base,
+                })
+                .collect())
+            .collect()
+    }
+
+    // check every lookup against comparing with every known UMI
+    fn check_against_scan(umi_length: usize, radius: usize, distance: UMIDistance) {
+        let known = umis(300, umi_length, 7);
+        let mut index = UMIIndex::new(umi_length, radius, distance);
+        for umi in &known {
+            index.insert(umi);
+        }
+        let distance_function = distance.function();
+        let wildcards = |umi: &UMIVec| umi.iter().filter(|base| **base == b'N').count();
+
+        for query in umis(200, umi_length, 5).iter().chain(&known) {
+            let mut expected = known.iter().unique()
+                .map(|umi| (distance_function(umi, query), umi))
+                .filter(|(found_distance, _)| *found_distance <= radius as u64)
+                .collect::<Vec<_>>();
+            let mut found = index.find_within(query);
+            expected.sort_unstable();
+            found.sort_unstable();
+            assert_eq!(found, expected, "find_within {}", String::from_utf8_lossy(query));
+
+            let nearest = match known.contains(query) {
+                true => Some(query),
+                false => expected.iter()
+                    .min_by_key(|(found_distance, umi)| (*found_distance, wildcards(umi), *umi))
+                    .map(|(_, umi)| *umi),
+            };
+            assert_eq!(index.find_nearest(query), nearest, "find_nearest {}", String::from_utf8_lossy(query));
+        }
+    }
+
+    #[test]
+    fn hamming_matches_scan() {
+        for radius in 1..=3 {
+            check_against_scan(8, radius, UMIDistance::Hamming);
+        }
+    }
+
+    #[test]
+    fn levenshtein_matches_scan() {
+        for radius in 1..=3 {
+            check_against_scan(10, radius, UMIDistance::Levenshtein);
+        }
+    }
+
+    #[test]
+    fn no_segments_falls_back_to_scan() {
+        // more errors allowed than there are bases to split into segments
+        assert!(UMIIndex::new(1, 3, UMIDistance::Hamming).segments.is_empty());
+        assert!(UMIIndex::new(3, 2, UMIDistance::Levenshtein).segments.is_empty());
+        check_against_scan(1, 3, UMIDistance::Hamming);
+        check_against_scan(3, 2, UMIDistance::Levenshtein);
+    }
+
+    #[test]
+    fn removed_umis_are_not_found() {
+        let mut index = UMIIndex::new(6, 1, UMIDistance::Hamming);
+        index.insert(&b"ACGNAC".to_vec());
+        index.insert(&b"TTTTTT".to_vec());
+        index.remove(&b"ACGNAC".to_vec());
+        assert_eq!(index.find_nearest(b"ACGTAC"), None);
+        assert_eq!(index.find_nearest(b"TTTTTA"), Some(&b"TTTTTT".to_vec()));
+    }
+
+    // lookups should cost about the same however many UMIs are known. a benchmark rather than a check, so ignored by
+    // default: cargo test --release lookup_scaling -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn lookup_scaling() {
+        let queries = umis(10_000, 12, usize::MAX);
+        for radius in 1..=3 {
+            for known_count in [10_000, 100_000, 1_000_000, 10_000_000] {
+                let mut index = UMIIndex::new(12, radius, UMIDistance::Hamming);
+                for umi in umis(known_count, 12, usize::MAX) {
+                    index.insert(&umi);
+                }
+
+                let started = std::time::Instant::now();
+                let candidates = queries.iter().map(|query| index.candidates(query).len()).sum::<usize>();
+                for query in &queries {
+                    index.find_nearest(query);
+                }
+                println!("radius {radius}, {known_count:>8} known UMIs: {:>8.2} µs and {:>8.2} candidates per lookup",
+                         started.elapsed().as_secs_f64() * 1e6 / queries.len() as f64 / 2.0,
+                         candidates as f64 / queries.len() as f64);
+            }
+        }
+    }
+}
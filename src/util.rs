@@ -1,7 +1,7 @@
 use bio::alphabets::dna;
 use bio::utils::TextSlice;
 
-fn check_primer_base(bases: (&u8, &u8)) -> bool {
+pub(crate) fn check_primer_base(bases: (&u8, &u8)) -> bool {
     let (primer_base, seq_base) = bases;
     match primer_base.to_ascii_uppercase() {
         b'A' | b'T' | b'C' | b'G' => primer_base.eq_ignore_ascii_case(seq_base),
@@ -12,10 +12,10 @@ fn check_primer_base(bases: (&u8, &u8)) -> bool {
         b'K' => "TG".contains(seq_base.to_ascii_uppercase() as char),
         b'S' => "CG".contains(seq_base.to_ascii_uppercase() as char),
         // already verified this is valid fully specified DNA alphabet
-        b'B' => seq_base.to_ascii_uppercase() != b'A',
-        b'V' => seq_base.to_ascii_uppercase() != b'T',
-        b'D' => seq_base.to_ascii_uppercase() != b'C',
-        b'H' => seq_base.to_ascii_uppercase() != b'G',
+        b'B' => !seq_base.eq_ignore_ascii_case(&b'A'),
+        b'V' => !seq_base.eq_ignore_ascii_case(&b'T'),
+        b'D' => !seq_base.eq_ignore_ascii_case(&b'C'),
+        b'H' => !seq_base.eq_ignore_ascii_case(&b'G'),
         // why is this in a primer
         b'N' => true,
         _ => unimplemented!()
@@ -33,5 +33,5 @@ pub(crate) fn check_primer(primer: TextSlice, seq: TextSlice) -> Result<bool, &'
         return Ok(false);
     }
 
-    return Ok(primer.iter().zip(seq.iter().take(primer.len())).all(check_primer_base));
+    Ok(primer.iter().zip(seq.iter().take(primer.len())).all(check_primer_base))
 }
\ No newline at end of file
@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+
+use itertools::Itertools;
+
+use crate::types::UMIVec;
+use crate::umi_distance::UMIDistance;
+use crate::umi_index::UMIIndex;
+
+// one sequence per line; anything after the first whitespace (e.g. counts, as umi_tools writes) and lines starting
+// with # are ignored
+pub(crate) fn read_whitelist(path_buf: &PathBuf) -> io::Result<Vec<UMIVec>> {
+    let mut entries = vec![];
+    for line in BufReader::new(File::open(path_buf)?).lines() {
+        let line = line?;
+        match line.split_whitespace().next() {
+            Some(entry) if !entry.starts_with('#') => entries.push(entry.to_ascii_uppercase().into_bytes()),
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+pub(crate) enum WhitelistMiss {
+    Unmatched,
+    Ambiguous,
+}
+
+// a fixed set of known sequences; observed sequences are corrected to the one known sequence closest to them
+pub(crate) struct Whitelist {
+    index: UMIIndex,
+}
+
+impl Whitelist {
+    pub(crate) fn new(entries: &[UMIVec], radius: usize, distance: UMIDistance) -> Self {
+        let mut index = UMIIndex::new(entries.first().map_or(0, |entry| entry.len()), radius, distance);
+        for entry in entries {
+            index.insert(entry);
+        }
+
+        Whitelist { index }
+    }
+
+    pub(crate) fn correct(&self, observed: &[u8]) -> Result<UMIVec, WhitelistMiss> {
+        let found = self.index.find_within(observed);
+        let Some(best) = found.iter().map(|(distance, _)| *distance).min() else {
+            return Err(WhitelistMiss::Unmatched);
+        };
+
+        match found.into_iter().filter(|(distance, _)| *distance == best).exactly_one() {
+            Ok((_, entry)) => Ok(entry.clone()),
+            Err(_) => Err(WhitelistMiss::Ambiguous),
+        }
+    }
+}
+
+// with counts sorted most abundant first, how many sit before the knee of the cumulative count curve: the point
+// furthest from the straight line between its ends, as umi_tools whitelist's default method does
+pub(crate) fn knee_point(counts: &[usize]) -> usize {
+    if counts.len() < 3 {
+        return counts.len();
+    }
+
+    let cumulative = counts.iter()
+        .scan(0, |total, count| {
+            *total += count;
+            Some(*total as f64)
+        })
+        .collect::<Vec<_>>();
+
+    // line from (0, first) to (last index, last)
+    let (x_end, y_start, y_end) = ((cumulative.len() - 1) as f64, cumulative[0], *cumulative.last().unwrap());
+    let length = (x_end * x_end + (y_end - y_start) * (y_end - y_start)).sqrt();
+    let distance = |(x, y): (usize, &f64)| ((y_end - y_start) * x as f64 - x_end * (y - y_start)).abs() / length;
+
+    cumulative.iter().enumerate()
+        .map(|(x, y)| (x, distance((x, y))))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(counts.len(), |(x, _)| x + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn whitelist() -> Whitelist {
+        Whitelist::new(&[b"AAAAAAAA".to_vec(), b"AAAATTTT".to_vec(), b"CCCCCCCC".to_vec()], 2, UMIDistance::Hamming)
+    }
+
+    #[test]
+    fn corrects_to_the_closest_entry() {
+        assert!(matches!(whitelist().correct(b"CCCCCCCC"), Ok(entry) if entry == b"CCCCCCCC"));
+        // one off AAAAAAAA, three off AAAATTTT
+        assert!(matches!(whitelist().correct(b"AAAAAAAT"), Ok(entry) if entry == b"AAAAAAAA"));
+        assert!(matches!(whitelist().correct(b"AAAATTTA"), Ok(entry) if entry == b"AAAATTTT"));
+    }
+
+    #[test]
+    fn ties_are_ambiguous_and_nothing_within_radius_is_unmatched() {
+        // two off both AAAAAAAA and AAAATTTT
+        assert!(matches!(whitelist().correct(b"AAAAAATT"), Err(WhitelistMiss::Ambiguous)));
+        assert!(matches!(whitelist().correct(b"GGGGGGGG"), Err(WhitelistMiss::Unmatched)));
+        assert!(matches!(whitelist().correct(b"CCCCCGGG"), Err(WhitelistMiss::Unmatched)));
+    }
+
+    #[test]
+    fn knee_after_the_abundant_head() {
+        let counts = [vec![1000; 5], vec![1; 100]].concat();
+        assert_eq!(knee_point(&counts), 5);
+        let counts = [vec![1000, 900, 800, 700], vec![3, 2, 2, 1, 1, 1, 1, 1]].concat();
+        assert_eq!(knee_point(&counts), 4);
+    }
+
+    #[test]
+    fn too_few_counts_for_a_knee() {
+        assert_eq!(knee_point(&[]), 0);
+        assert_eq!(knee_point(&[10, 1]), 2);
+    }
+}
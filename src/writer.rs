@@ -1,13 +1,17 @@
+use std::fmt::Display;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{BufWriter, ErrorKind, Seek, SeekFrom, Write};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
 use std::path::PathBuf;
-use std::process::exit;
 
 use bio::io::fastq;
 use flate2::Compression;
 use flate2::write::GzEncoder;
+use itertools::Itertools;
 
+use crate::error::GrebeError;
+
+#[allow(clippy::upper_case_acronyms)]
 pub(crate) enum WriterMaybeGzip {
     GZIP(GzEncoder<File>),
     UNCOMPRESSED(File),
@@ -32,46 +36,111 @@ impl Write for WriterMaybeGzip {
     }
 }
 
-pub(crate) fn writer_maybe_gzip(path_buf: &PathBuf) -> Result<(fastq::Writer<WriterMaybeGzip>, bool), io::Error> {
-    let mut file = OpenOptions::new().write(true).create(true).open(path_buf)?;
-    if file.seek(SeekFrom::End(0)).unwrap() > 0 {
-        return Err(io::Error::other(""));
+fn writer_maybe_gzip(path_buf: &PathBuf) -> Result<(WriterMaybeGzip, bool), GrebeError> {
+    let open_error = |err| GrebeError::Open(path_buf.clone(), err);
+    let mut file = OpenOptions::new().write(true).create(true).truncate(false).open(path_buf).map_err(open_error)?;
+    if file.seek(SeekFrom::End(0)).map_err(open_error)? > 0 {
+        return Err(GrebeError::OverwriteRefused(path_buf.clone()));
     }
 
-    file.seek(SeekFrom::Start(0))?;
+    file.seek(SeekFrom::Start(0)).map_err(open_error)?;
 
     if match path_buf.extension() {
         Some(ext) if ext == "gzip" || ext == "gz" => true,
         Some(_) => false,
         None => false,
     } {
-        Ok((fastq::Writer::from_bufwriter(BufWriter::new(
-            WriterMaybeGzip::GZIP(GzEncoder::new(file, Compression::default())))), true))
+        Ok((WriterMaybeGzip::GZIP(GzEncoder::new(file, Compression::default())), true))
     } else {
-        Ok((fastq::Writer::from_bufwriter(BufWriter::new(WriterMaybeGzip::UNCOMPRESSED(file))), false))
+        Ok((WriterMaybeGzip::UNCOMPRESSED(file), false))
     }
 }
 
-pub(crate) fn writer_from_path(maybe_path_buf: Option<&PathBuf>) -> fastq::Writer<WriterMaybeGzip> {
+fn raw_writer_from_path(maybe_path_buf: Option<&PathBuf>) -> Result<WriterMaybeGzip, GrebeError> {
     match maybe_path_buf {
-        Some(path_buf) => match writer_maybe_gzip(path_buf) {
-            Ok((result, was_compressed)) => {
-                if was_compressed { eprintln!("info: writing {} as a gzip", path_buf.display()) }
-                result
-            }
-            Err(err) => {
-                match err.kind() {
-                    ErrorKind::Other => eprintln!("refusing to overwrite nonempty file {}", path_buf.display()),
-                    _ => eprintln!("couldn't open output {} for writing", path_buf.display())
-                }
-                exit(1);
-            }
-        },
-        None => fastq::Writer::from_bufwriter(BufWriter::new(WriterMaybeGzip::NULL(io::empty())))
+        Some(path_buf) => {
+            let (result, was_compressed) = writer_maybe_gzip(path_buf)?;
+            if was_compressed { eprintln!("info: writing {} as a gzip", path_buf.display()) }
+            Ok(result)
+        }
+        None => Ok(WriterMaybeGzip::NULL(io::empty()))
+    }
+}
+
+// a FASTQ output that remembers where it goes, so failures can say which file they happened to
+pub(crate) struct RecordWriter {
+    // empty for outputs nobody asked for, which can't fail
+    path_buf: PathBuf,
+    writer: fastq::Writer<WriterMaybeGzip>,
+}
+
+impl Default for RecordWriter {
+    fn default() -> Self {
+        RecordWriter {
+            path_buf: Default::default(),
+            writer: fastq::Writer::from_bufwriter(BufWriter::new(WriterMaybeGzip::NULL(io::empty()))),
+        }
+    }
+}
+
+impl RecordWriter {
+    pub(crate) fn write(&mut self, id: &str, desc: Option<&str>, seq: &[u8], qual: &[u8]) -> Result<(), GrebeError> {
+        self.writer.write(id, desc, seq, qual).map_err(|err| GrebeError::Write(self.path_buf.clone(), err))
+    }
+
+    // a full disk may only show up once buffered records are pushed out, which dropping the writer would ignore
+    pub(crate) fn flush(&mut self) -> Result<(), GrebeError> {
+        self.writer.flush().map_err(|err| GrebeError::Write(self.path_buf.clone(), err))
+    }
+}
+
+pub(crate) fn writer_from_path(maybe_path_buf: Option<&PathBuf>) -> Result<RecordWriter, GrebeError> {
+    Ok(RecordWriter {
+        path_buf: maybe_path_buf.cloned().unwrap_or_default(),
+        writer: fastq::Writer::from_bufwriter(BufWriter::new(raw_writer_from_path(maybe_path_buf)?)),
+    })
+}
+
+// for side outputs that aren't reads (tables, lists)
+pub(crate) fn text_writer_from_path(maybe_path_buf: Option<&PathBuf>)
+                                    -> Result<BufWriter<WriterMaybeGzip>, GrebeError> {
+    Ok(BufWriter::new(raw_writer_from_path(maybe_path_buf)?))
+}
+
+// a tab-separated output that, like RecordWriter, remembers where it goes
+pub(crate) struct TableWriter {
+    path_buf: PathBuf,
+    writer: BufWriter<WriterMaybeGzip>,
+}
+
+impl Default for TableWriter {
+    fn default() -> Self {
+        TableWriter {
+            path_buf: Default::default(),
+            writer: BufWriter::new(WriterMaybeGzip::NULL(io::empty())),
+        }
     }
 }
 
+impl TableWriter {
+    pub(crate) fn write_row(&mut self, fields: &[&dyn Display]) -> Result<(), GrebeError> {
+        writeln!(self.writer, "{}", fields.iter().join("\t"))
+            .map_err(|err| GrebeError::Write(self.path_buf.clone(), err))
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<(), GrebeError> {
+        self.writer.flush().map_err(|err| GrebeError::Write(self.path_buf.clone(), err))
+    }
+}
+
+pub(crate) fn table_writer_from_path(maybe_path_buf: Option<&PathBuf>) -> Result<TableWriter, GrebeError> {
+    Ok(TableWriter {
+        path_buf: maybe_path_buf.cloned().unwrap_or_default(),
+        writer: text_writer_from_path(maybe_path_buf)?,
+    })
+}
+
 pub(crate) fn make_writer_pair(output_paths: (Option<&PathBuf>, Option<&PathBuf>))
-                               -> (fastq::Writer<WriterMaybeGzip>, fastq::Writer<WriterMaybeGzip>) {
-    (writer_from_path(output_paths.0), writer_from_path(output_paths.1))
+                               -> Result<(RecordWriter, RecordWriter), GrebeError> {
+    Ok((writer_from_path(output_paths.0)?, writer_from_path(output_paths.1)?))
 }